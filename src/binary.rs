@@ -0,0 +1,513 @@
+//! Binary format for the output of `parse`
+//!
+//! The file starts with a magic string, the format version and the length of
+//! the header, which is JSON and contains the provenance, the sample list, the
+//! annotation table, the order of the annotations (UIDs) and the list of
+//! columns. The header is followed by the data, one block per column. In each
+//! block the values are stored by gene, with one value per sample, so the value
+//! for gene `g` in sample `s` is at `g * n_samples + s`. All numbers are little
+//! endian.
+//!
+//! The columns are found by name in the header, the version is increased when
+//! columns are added or changed: version 2 added the extra counts.
+//!
+//! Annotations below the coverage threshold in a sample are marked as missing
+//! (`u32::MAX` for integers and `NaN` for floats). The columns with the extra
+//! counts are optional when reading, as older files don't have them. The site
+//! frequency spectrum, if present, is stored with one column per bin, named
+//! `sfs_syn_<bin>` and `sfs_nonsyn_<bin>`.
+//!
+//! The file is read through memory mapping, the column blocks are not copied
+//! and only the pages of the columns used are loaded.
+use super::data::{AnnotationTable, ExtraCounts, ParseOutput, SampleExtra};
+use super::parse::SamplePnPs;
+use super::provenance::Provenance;
+use anyhow::{bail, Context, Result};
+use bio_rascal::snps::PnPs;
+use log::info;
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::Path;
+use uuid::Uuid;
+
+pub static MAGIC: &[u8; 8] = b"PNPSBIN\0";
+/// Current version of the format, files with a higher version are rejected
+pub const FORMAT_VERSION: u32 = 2;
+/// Magic, version and header length
+const PREAMBLE_LENGTH: usize = 8 + 4 + 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum DataType {
+    U32,
+    F64,
+}
+
+impl DataType {
+    fn size(&self) -> usize {
+        match self {
+            Self::U32 => 4,
+            Self::F64 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Column {
+    name: String,
+    dtype: DataType,
+    /// Offset of the block from the start of the data
+    offset: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BinaryHeader {
+    #[serde(default)]
+    provenance: Vec<Provenance>,
+    samples: Vec<String>,
+    uids: Vec<Uuid>,
+    annotations: AnnotationTable,
+    columns: Vec<Column>,
+}
+
+impl BinaryHeader {
+    fn get_column(&self, name: &str) -> Result<&Column> {
+        match self.columns.iter().find(|c| c.name == name) {
+            None => bail!("Column {} not found in file", name),
+            Some(column) => Ok(column),
+        }
+    }
+}
+
+/// Columns written for each annotation and sample
+static COLUMNS: [(&str, DataType); 5] = [
+    ("coverage", DataType::U32),
+    ("exp_syn", DataType::F64),
+    ("exp_nonsyn", DataType::F64),
+    ("syn", DataType::U32),
+    ("nonsyn", DataType::U32),
+];
+
+/// Columns with the extra counts, written after `COLUMNS`
+static EXTRA_COLUMNS: [(&str, DataType); 11] = [
+    ("stop_gain", DataType::U32),
+    ("stop_loss", DataType::U32),
+    ("inframe_indel", DataType::U32),
+    ("frameshift_indel", DataType::U32),
+    ("fractional_syn", DataType::F64),
+    ("fractional_nonsyn", DataType::F64),
+    ("kappa", DataType::F64),
+    ("pi", DataType::F64),
+    ("pi_syn", DataType::F64),
+    ("pi_nonsyn", DataType::F64),
+    ("segregating_sites", DataType::U32),
+];
+
+/// Returns true for synonymous and the bin, if the column is part of the site
+/// frequency spectrum
+fn sfs_column(name: &str) -> Option<(bool, usize)> {
+    if let Some(bin) = name.strip_prefix("sfs_syn_") {
+        return Some((true, bin.parse().ok()?));
+    }
+    let bin = name.strip_prefix("sfs_nonsyn_")?;
+    Some((false, bin.parse().ok()?))
+}
+
+/// Returns the value for the column, `None` if the extra counts are missing
+fn column_value(name: &str, pnps: &PnPs, extra: Option<&ExtraCounts>) -> Option<f64> {
+    match name {
+        "coverage" => Some(pnps.coverage as f64),
+        "exp_syn" => Some(pnps.exp_syn),
+        "exp_nonsyn" => Some(pnps.exp_nonsyn),
+        "syn" => Some(pnps.syn as f64),
+        "nonsyn" => Some(pnps.nonsyn as f64),
+        "stop_gain" => extra.map(|e| e.stop_gain as f64),
+        "stop_loss" => extra.map(|e| e.stop_loss as f64),
+        "inframe_indel" => extra.map(|e| e.inframe_indel as f64),
+        "frameshift_indel" => extra.map(|e| e.frameshift_indel as f64),
+        "fractional_syn" => extra.and_then(|e| e.fractional_syn),
+        "fractional_nonsyn" => extra.and_then(|e| e.fractional_nonsyn),
+        "kappa" => extra.and_then(|e| e.kappa),
+        "pi" => extra.map(|e| e.pi),
+        "pi_syn" => extra.map(|e| e.pi_syn),
+        "pi_nonsyn" => extra.map(|e| e.pi_nonsyn),
+        "segregating_sites" => extra.map(|e| e.segregating_sites as f64),
+        name => match (sfs_column(name), extra) {
+            (Some((true, bin)), Some(extra)) => extra.sfs_syn.get(bin).map(|v| *v as f64),
+            (Some((false, bin)), Some(extra)) => extra.sfs_nonsyn.get(bin).map(|v| *v as f64),
+            (Some(_), None) => None,
+            (None, _) => unreachable!("Unknown column {}", name),
+        },
+    }
+}
+
+fn set_extra_value(name: &str, extra: &mut ExtraCounts, value: f64) {
+    match name {
+        "stop_gain" => extra.stop_gain = value as u32,
+        "stop_loss" => extra.stop_loss = value as u32,
+        "inframe_indel" => extra.inframe_indel = value as u32,
+        "frameshift_indel" => extra.frameshift_indel = value as u32,
+        "fractional_syn" => extra.fractional_syn = Some(value),
+        "fractional_nonsyn" => extra.fractional_nonsyn = Some(value),
+        "kappa" => extra.kappa = Some(value),
+        "pi" => extra.pi = value,
+        "pi_syn" => extra.pi_syn = value,
+        "pi_nonsyn" => extra.pi_nonsyn = value,
+        "segregating_sites" => extra.segregating_sites = value as u32,
+        name => {
+            let (syn, bin) = match sfs_column(name) {
+                None => unreachable!("Unknown column {}", name),
+                Some(value) => value,
+            };
+            let sfs = match syn {
+                true => &mut extra.sfs_syn,
+                false => &mut extra.sfs_nonsyn,
+            };
+            if sfs.len() <= bin {
+                sfs.resize(bin + 1, 0);
+            }
+            sfs[bin] = value as u32;
+        }
+    }
+}
+
+/// Returns true if the file starts with the magic string of the binary format
+pub fn is_binary_file<P: AsRef<Path>>(file_name: P) -> Result<bool> {
+    let mut handle = File::open(&file_name)
+        .with_context(|| format!("Cannot open file {}", file_name.as_ref().display()))?;
+    let mut buffer = [0u8; 8];
+    match handle.read_exact(&mut buffer) {
+        Err(_) => Ok(false),
+        Ok(_) => Ok(&buffer == MAGIC),
+    }
+}
+
+pub fn write_binary<P: AsRef<Path>>(file_name: P, parse_output: &ParseOutput) -> Result<()> {
+    info!("Writing binary file: {}", file_name.as_ref().display());
+    let samples = &parse_output.samples;
+    let annotations = &parse_output.annotations;
+    let mut sample_ids: Vec<String> = samples.keys().cloned().collect();
+    sample_ids.sort();
+    let mut uids: Vec<Uuid> = annotations.keys().cloned().collect();
+    uids.sort();
+
+    let n_sfs_bins = parse_output
+        .extra
+        .values()
+        .flat_map(|m| m.values())
+        .map(|e| e.sfs_syn.len().max(e.sfs_nonsyn.len()))
+        .max()
+        .unwrap_or_default();
+    let mut column_names: Vec<(String, DataType)> = COLUMNS
+        .iter()
+        .chain(EXTRA_COLUMNS.iter())
+        .map(|(name, dtype)| (name.to_string(), *dtype))
+        .collect();
+    for prefix in ["sfs_syn", "sfs_nonsyn"] {
+        for bin in 0..n_sfs_bins {
+            column_names.push((format!("{}_{}", prefix, bin), DataType::U32));
+        }
+    }
+
+    let n_values = (uids.len() * sample_ids.len()) as u64;
+    let mut offset = 0u64;
+    let columns: Vec<Column> = column_names
+        .into_iter()
+        .map(|(name, dtype)| {
+            let column = Column {
+                name,
+                dtype,
+                offset,
+            };
+            offset += n_values * dtype.size() as u64;
+            column
+        })
+        .collect();
+
+    let header = BinaryHeader {
+        provenance: parse_output.provenance.clone(),
+        samples: sample_ids,
+        uids,
+        annotations: annotations.clone(),
+        columns,
+    };
+    let header_bytes = serde_json::to_vec(&header).context("Cannot serialize header")?;
+
+    let mut writer = BufWriter::new(
+        File::create(&file_name)
+            .with_context(|| format!("Cannot create file {}", file_name.as_ref().display()))?,
+    );
+    writer.write_all(MAGIC)?;
+    writer.write_all(&FORMAT_VERSION.to_le_bytes())?;
+    writer.write_all(&(header_bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(&header_bytes)?;
+
+    let sample_maps: Vec<&HashMap<Uuid, PnPs>> =
+        header.samples.iter().map(|s| &samples[s]).collect();
+    let extra_maps: Vec<Option<&HashMap<Uuid, ExtraCounts>>> = header
+        .samples
+        .iter()
+        .map(|s| parse_output.extra.get(s))
+        .collect();
+
+    for column in header.columns.iter() {
+        for uid in header.uids.iter() {
+            for (sample_map, extra_map) in sample_maps.iter().zip(extra_maps.iter()) {
+                let extra = extra_map.and_then(|m| m.get(uid));
+                let value = sample_map
+                    .get(uid)
+                    .and_then(|p| column_value(&column.name, p, extra));
+                match (column.dtype, value) {
+                    (DataType::U32, None) => writer.write_all(&u32::MAX.to_le_bytes())?,
+                    (DataType::U32, Some(value)) => {
+                        writer.write_all(&(value as u32).to_le_bytes())?
+                    }
+                    (DataType::F64, value) => {
+                        writer.write_all(&value.unwrap_or(f64::NAN).to_le_bytes())?
+                    }
+                }
+            }
+        }
+    }
+
+    writer.flush()?;
+    Ok(())
+}
+
+/// Values of a column block, in the mapped file
+struct ColumnReader<'a> {
+    data: &'a [u8],
+    dtype: DataType,
+}
+
+impl<'a> ColumnReader<'a> {
+    /// Returns the block of the column, `data` starts after the header
+    fn new(data: &'a [u8], column: &Column, n_values: usize) -> Result<Self> {
+        let start = column.offset as usize;
+        let end = start + n_values * column.dtype.size();
+        if data.len() < end {
+            bail!("File is truncated, cannot read column {}", column.name);
+        }
+        Ok(Self {
+            data: &data[start..end],
+            dtype: column.dtype,
+        })
+    }
+
+    /// Returns the value at index, `None` if missing
+    fn get(&self, index: usize) -> Option<f64> {
+        match self.dtype {
+            DataType::U32 => {
+                let start = index * 4;
+                let value = u32::from_le_bytes(self.data[start..start + 4].try_into().unwrap());
+                match value {
+                    u32::MAX => None,
+                    value => Some(value as f64),
+                }
+            }
+            DataType::F64 => {
+                let start = index * 8;
+                let value = f64::from_le_bytes(self.data[start..start + 8].try_into().unwrap());
+                match value.is_nan() {
+                    true => None,
+                    false => Some(value),
+                }
+            }
+        }
+    }
+}
+
+pub fn read_binary<P: AsRef<Path>>(file_name: P) -> Result<ParseOutput> {
+    info!("Reading binary file: {}", file_name.as_ref().display());
+    let handle = File::open(&file_name)
+        .with_context(|| format!("Cannot open file {}", file_name.as_ref().display()))?;
+    // the file must not be modified while it is mapped
+    let mmap = unsafe { Mmap::map(&handle) }.context("Cannot map file in memory")?;
+
+    if mmap.len() < PREAMBLE_LENGTH || &mmap[..8] != MAGIC {
+        bail!("Not a pnps-utils binary file");
+    }
+    let version = u32::from_le_bytes(mmap[8..12].try_into().unwrap());
+    if version > FORMAT_VERSION {
+        bail!(
+            "Binary format version {} is not supported, the latest is {}",
+            version,
+            FORMAT_VERSION
+        );
+    }
+    let header_length = u64::from_le_bytes(mmap[12..20].try_into().unwrap()) as usize;
+    if mmap.len() < PREAMBLE_LENGTH + header_length {
+        bail!("File is truncated, cannot read header");
+    }
+    let header: BinaryHeader =
+        serde_json::from_slice(&mmap[PREAMBLE_LENGTH..PREAMBLE_LENGTH + header_length])
+            .context("Cannot parse header")?;
+    let data = &mmap[PREAMBLE_LENGTH + header_length..];
+
+    let n_samples = header.samples.len();
+    let n_values = header.uids.len() * n_samples;
+    let read_column = |column: &Column| ColumnReader::new(data, column, n_values);
+    let coverage = read_column(header.get_column("coverage")?)?;
+    let exp_syn = read_column(header.get_column("exp_syn")?)?;
+    let exp_nonsyn = read_column(header.get_column("exp_nonsyn")?)?;
+    let syn = read_column(header.get_column("syn")?)?;
+    let nonsyn = read_column(header.get_column("nonsyn")?)?;
+    let mut extra_columns: Vec<(&str, ColumnReader)> = vec![];
+    for column in header.columns.iter() {
+        let is_extra = EXTRA_COLUMNS.iter().any(|(name, _)| *name == column.name);
+        if is_extra || sfs_column(&column.name).is_some() {
+            extra_columns.push((&column.name, read_column(column)?));
+        }
+    }
+
+    let mut samples = SamplePnPs::with_capacity(n_samples);
+    let mut extra = SampleExtra::with_capacity(n_samples);
+    for (sample_index, sample_id) in header.samples.iter().enumerate() {
+        let mut sample_map: HashMap<Uuid, PnPs> = HashMap::new();
+        let mut extra_map: HashMap<Uuid, ExtraCounts> = HashMap::new();
+        for (gene_index, uid) in header.uids.iter().enumerate() {
+            let index = gene_index * n_samples + sample_index;
+            let coverage = match coverage.get(index) {
+                None => continue,
+                Some(value) => value,
+            };
+            let pnps = PnPs {
+                uid: *uid,
+                coverage: coverage as _,
+                exp_syn: exp_syn.get(index).unwrap_or_default(),
+                exp_nonsyn: exp_nonsyn.get(index).unwrap_or_default(),
+                syn: syn.get(index).unwrap_or_default() as _,
+                nonsyn: nonsyn.get(index).unwrap_or_default() as _,
+                ..Default::default()
+            };
+            sample_map.insert(*uid, pnps);
+            // the extra counts are missing if not available when writing
+            let mut counts: Option<ExtraCounts> = None;
+            for (name, column) in extra_columns.iter() {
+                if let Some(value) = column.get(index) {
+                    set_extra_value(name, counts.get_or_insert_with(Default::default), value);
+                }
+            }
+            if let Some(counts) = counts {
+                extra_map.insert(*uid, counts);
+            }
+        }
+        samples.insert(sample_id.clone(), sample_map);
+        if !extra_map.is_empty() {
+            extra.insert(sample_id.clone(), extra_map);
+        }
+    }
+
+    Ok(ParseOutput {
+        provenance: header.provenance,
+        annotations: header.annotations,
+        samples,
+        extra,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::data::AnnotationInfo;
+    use super::*;
+
+    fn annotation_info(start: usize, end: usize) -> AnnotationInfo {
+        AnnotationInfo {
+            seq_id: "contig_1".to_string(),
+            start,
+            end,
+            strand: "+".to_string(),
+            attributes: HashMap::from([("locus_tag".to_string(), format!("gene_{}", start))]),
+        }
+    }
+
+    fn pnps(uid: Uuid, syn: u32, nonsyn: u32) -> PnPs {
+        PnPs {
+            uid,
+            exp_syn: 25.5,
+            exp_nonsyn: 74.5,
+            syn,
+            nonsyn,
+            coverage: 10,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let uid1 = Uuid::from_u128(1);
+        let uid2 = Uuid::from_u128(2);
+        let extra = ExtraCounts {
+            stop_gain: 1,
+            frameshift_indel: 2,
+            fractional_syn: Some(1.5),
+            fractional_nonsyn: Some(2.5),
+            pi: 0.25,
+            segregating_sites: 4,
+            sfs_syn: vec![1, 0],
+            sfs_nonsyn: vec![2, 3],
+            kappa: Some(2.),
+            ..Default::default()
+        };
+        // S2 has only one gene and no extra counts
+        let parse_output = ParseOutput {
+            provenance: vec![],
+            annotations: HashMap::from([
+                (uid1, annotation_info(1, 300)),
+                (uid2, annotation_info(400, 900)),
+            ]),
+            samples: HashMap::from([
+                (
+                    "S1".to_string(),
+                    HashMap::from([(uid1, pnps(uid1, 2, 3)), (uid2, pnps(uid2, 0, 1))]),
+                ),
+                ("S2".to_string(), HashMap::from([(uid2, pnps(uid2, 4, 5))])),
+            ]),
+            extra: HashMap::from([("S1".to_string(), HashMap::from([(uid1, extra)]))]),
+        };
+
+        let file_name =
+            std::env::temp_dir().join(format!("pnps-utils-test-{}.bin", std::process::id()));
+        write_binary(&file_name, &parse_output).unwrap();
+        assert!(is_binary_file(&file_name).unwrap());
+        let read_output = read_binary(&file_name);
+        std::fs::remove_file(&file_name).unwrap();
+        let read_output = read_output.unwrap();
+
+        assert_eq!(read_output.annotations.len(), 2);
+        assert_eq!(read_output.annotations[&uid2].start, 400);
+        assert_eq!(
+            read_output.annotations[&uid1].attributes["locus_tag"],
+            "gene_1"
+        );
+        assert_eq!(read_output.samples["S1"].len(), 2);
+        assert_eq!(read_output.samples["S2"].len(), 1);
+        let value = &read_output.samples["S2"][&uid2];
+        assert_eq!((value.syn, value.nonsyn, value.coverage), (4, 5, 10));
+        assert_eq!((value.exp_syn, value.exp_nonsyn), (25.5, 74.5));
+
+        assert!(!read_output.extra.contains_key("S2"));
+        assert!(!read_output.extra["S1"].contains_key(&uid2));
+        let value = &read_output.extra["S1"][&uid1];
+        assert_eq!((value.stop_gain, value.stop_loss), (1, 0));
+        assert_eq!(value.frameshift_indel, 2);
+        assert_eq!(value.fractional_syn, Some(1.5));
+        assert_eq!(value.fractional_nonsyn, Some(2.5));
+        assert_eq!(value.pi, 0.25);
+        assert_eq!(value.segregating_sites, 4);
+        assert_eq!(value.sfs_syn, [1, 0]);
+        assert_eq!(value.sfs_nonsyn, [2, 3]);
+        assert_eq!(value.kappa, Some(2.));
+    }
+
+    #[test]
+    fn test_sfs_column() {
+        assert_eq!(sfs_column("sfs_syn_3"), Some((true, 3)));
+        assert_eq!(sfs_column("sfs_nonsyn_0"), Some((false, 0)));
+        assert_eq!(sfs_column("sfs_syn_x"), None);
+        assert_eq!(sfs_column("syn"), None);
+    }
+}
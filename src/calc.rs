@@ -1,41 +1,341 @@
+use super::cli::{Aggregate, LabelAttribute, MapKey, NeutralityTest, ProvenanceOutput};
+use super::data::{
+    read_parse_output, AnnotationTable, Counts, ExtraCounts, KeyResolver, SampleExtra,
+};
+use super::metadata::SampleMetadata;
 use super::parse::SamplePnPs;
+use super::provenance::Provenance;
+use super::stats::{benjamini_hochberg, binomial_test, fisher_exact, median, quantile};
 use anyhow::{Context, Result};
-use bio_rascal::snps::{CalculatePnPs, GroupPnPs};
+use bio_rascal::snps::{GroupPnPs, PnPs};
 use bio_rascal::taxon::Taxonomy;
 use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use rand_distr::{Binomial, Distribution};
+use std::cell::RefCell;
 use std::collections::{HashMap, HashSet};
-use std::io::BufRead;
-use std::path::Path;
+use std::fs::File;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 use std::str::FromStr;
 use uuid::Uuid;
 
 // TODO: account for situation where -g is passed but not `-l` or a taxonomy is
 
 #[allow(non_camel_case_types)]
+#[derive(Debug, Clone, Copy)]
 enum ResultType {
     pNpS,
     pN,
     pS,
 }
 
-type GeneMap = HashMap<Uuid, Vec<String>>;
+impl ResultType {
+    fn get_value(&self, counts: &Counts) -> f64 {
+        match self {
+            ResultType::pNpS => counts.get_pnps(),
+            ResultType::pN => counts.get_pn(),
+            ResultType::pS => counts.get_ps(),
+        }
+    }
+}
+
+/// Which value is written in the output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValueKind {
+    Estimate,
+    /// Lower bound of the bootstrap confidence interval
+    Lower,
+    /// Upper bound of the bootstrap confidence interval
+    Upper,
+    /// P-value of the neutrality test
+    PValue,
+    /// P-value of the neutrality test, adjusted for each sample
+    QValue,
+    /// Number of stop-gain SNPs
+    StopGain,
+    /// Number of stop-loss SNPs
+    StopLoss,
+    /// Number of in-frame indels
+    InframeIndel,
+    /// Number of frameshift indels
+    FrameshiftIndel,
+    /// Nucleotide diversity per site
+    Pi,
+    /// Ratio of nonsynonymous to synonymous nucleotide diversity
+    PiNPiS,
+    /// Watterson's θ per site
+    WattersonTheta,
+    TajimaD,
+}
+
+impl ValueKind {
+    /// True if the value of a group is calculated from the summed counts of
+    /// the genes, whatever the aggregation method
+    fn uses_summed_counts(&self) -> bool {
+        !matches!(
+            self,
+            ValueKind::Estimate | ValueKind::WattersonTheta | ValueKind::TajimaD
+        )
+    }
+}
+
+/// Hashes the seed and the values with FNV-1a, which, unlike the hashers in
+/// `std`, gives the same result with every Rust release
+fn hash_seed(seed: u64, values: &[f64]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    let bytes = values
+        .iter()
+        .flat_map(|value| value.to_bits().to_le_bytes());
+    for byte in seed.to_le_bytes().into_iter().chain(bytes) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+/// Lower and upper bound of the confidence interval, by the bits of the counts
+type IntervalCache = Rc<RefCell<HashMap<[u64; 4], (f64, f64)>>>;
+
+/// Bootstrap confidence intervals, resampling the SNPs of a gene or group
+#[derive(Debug, Clone)]
+struct Bootstrap {
+    replicates: u32,
+    seed: u64,
+    confidence: f64,
+    /// Intervals already calculated, shared by the lower and upper bound
+    /// outputs
+    intervals: IntervalCache,
+}
+
+impl Bootstrap {
+    /// Returns the lower and upper bound of the confidence interval, computed
+    /// once for the same counts (the pseudocount and result type are the same
+    /// in a run)
+    fn interval(&self, counts: &Counts, pseudocount: f64, result_type: &ResultType) -> (f64, f64) {
+        let key = [counts.syn, counts.nonsyn, counts.exp_syn, counts.exp_nonsyn]
+            .map(|value| value.to_bits());
+        if let Some(interval) = self.intervals.borrow().get(&key) {
+            return *interval;
+        }
+        let interval = self.resample(counts, pseudocount, result_type);
+        self.intervals.borrow_mut().insert(key, interval);
+        interval
+    }
+
+    /// The SNPs are resampled with replacement, so the number of
+    /// nonsynonymous SNPs in each replicate is drawn from a binomial
+    /// distribution, while the expected sites are fixed. The random generator
+    /// is seeded with the seed and the counts, so the results don't depend on
+    /// the order of the genes.
+    fn resample(&self, counts: &Counts, pseudocount: f64, result_type: &ResultType) -> (f64, f64) {
+        let n_snps = (counts.syn + counts.nonsyn).round() as u64;
+        if n_snps == 0 {
+            return (f64::NAN, f64::NAN);
+        }
+        let distribution = match Binomial::new(n_snps, counts.nonsyn / n_snps as f64) {
+            Err(_) => return (f64::NAN, f64::NAN),
+            Ok(distribution) => distribution,
+        };
+
+        let seed = hash_seed(
+            self.seed,
+            &[counts.syn, counts.nonsyn, counts.exp_syn, counts.exp_nonsyn],
+        );
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        let mut values: Vec<f64> = (0..self.replicates)
+            .map(|_| {
+                let nonsyn = distribution.sample(&mut rng);
+                let replicate = Counts {
+                    syn: (n_snps - nonsyn) as f64,
+                    nonsyn: nonsyn as f64,
+                    ..*counts
+                };
+                result_type.get_value(&replicate.with_pseudocount(pseudocount))
+            })
+            .collect();
+        values.sort_by(|a, b| a.total_cmp(b));
+        let alpha = (1. - self.confidence) / 2.;
+        (quantile(&values, alpha), quantile(&values, 1. - alpha))
+    }
+}
+
+/// Tests if the split between nonsynonymous and synonymous SNPs differs from
+/// the one expected under neutrality (pN/pS = 1), that is the split between
+/// nonsynonymous and synonymous sites. The pseudocount is not used.
+fn neutrality_test(counts: &Counts, test: NeutralityTest) -> f64 {
+    let syn = counts.syn.round() as u64;
+    let nonsyn = counts.nonsyn.round() as u64;
+    if syn + nonsyn == 0 {
+        return f64::NAN;
+    }
+    match test {
+        NeutralityTest::Binomial => binomial_test(
+            nonsyn,
+            syn + nonsyn,
+            counts.exp_nonsyn / (counts.exp_syn + counts.exp_nonsyn),
+        ),
+        NeutralityTest::Fisher => fisher_exact(
+            nonsyn,
+            syn,
+            counts.exp_nonsyn.round() as u64,
+            counts.exp_syn.round() as u64,
+        ),
+    }
+}
+
+/// Options used to calculate and format the values in the output
+#[derive(Debug, Clone)]
+struct ValueOptions {
+    result_type: ResultType,
+    aggregate: Aggregate,
+    kind: ValueKind,
+    bootstrap: Option<Bootstrap>,
+    neutrality_test: Option<NeutralityTest>,
+    /// Added to the synonymous and nonsynonymous counts
+    pseudocount: f64,
+    /// Written for infinite values, e.g. pS = 0
+    inf_value: String,
+    /// Written for undefined values, or missing in a sample
+    nan_value: String,
+}
+
+impl ValueOptions {
+    fn format_value(&self, value: f64) -> String {
+        if value.is_nan() {
+            self.nan_value.clone()
+        } else if value.is_infinite() {
+            self.inf_value.clone()
+        } else {
+            value.to_string()
+        }
+    }
+
+    /// Returns the estimate, a bound of the confidence interval, the p-value
+    /// of the neutrality test or one of the extra counts, for the counts
+    fn counts_value(&self, counts: &Counts) -> f64 {
+        match (self.kind, &self.bootstrap) {
+            (ValueKind::Lower, Some(bootstrap)) => {
+                bootstrap
+                    .interval(counts, self.pseudocount, &self.result_type)
+                    .0
+            }
+            (ValueKind::Upper, Some(bootstrap)) => {
+                bootstrap
+                    .interval(counts, self.pseudocount, &self.result_type)
+                    .1
+            }
+            (ValueKind::PValue | ValueKind::QValue, _) => match self.neutrality_test {
+                None => f64::NAN,
+                Some(test) => neutrality_test(counts, test),
+            },
+            (ValueKind::StopGain, _) => counts.stop_gain,
+            (ValueKind::StopLoss, _) => counts.stop_loss,
+            (ValueKind::InframeIndel, _) => counts.inframe_indel,
+            (ValueKind::FrameshiftIndel, _) => counts.frameshift_indel,
+            (ValueKind::Pi, _) => counts.get_pi(),
+            (ValueKind::PiNPiS, _) => counts.get_pinpis(),
+            (ValueKind::WattersonTheta, _) => counts.get_watterson_theta(),
+            (ValueKind::TajimaD, _) => counts.get_tajima_d(),
+            _ => self
+                .result_type
+                .get_value(&counts.with_pseudocount(self.pseudocount)),
+        }
+    }
+
+    fn gene_value(&self, pnps: &PnPs, extra: Option<&ExtraCounts>) -> f64 {
+        self.counts_value(&Counts::from_pnps(pnps).with_extra(extra))
+    }
+}
+
+pub type GeneMap = HashMap<Uuid, Vec<String>>;
 type TaxonMap = HashMap<Uuid, u32>;
 type LineageMap = HashMap<Uuid, String>;
-type SampleGroupPnPs<'a> = HashMap<String, HashMap<(String, u32, String), GroupPnPs<'a>>>;
+type BinMap = HashMap<Uuid, String>;
+/// Gene ID, Taxon ID, Lineage and Bin ID
+type GroupKey = (String, u32, String, String);
+type SampleGroupPnPs<'a> = HashMap<String, HashMap<GroupKey, GroupPnPs<'a>>>;
+
+/// Calculates the value for a group of genes, using the aggregation method
+/// requested. Genes with a value that is not finite are not used by the
+/// methods working on per-gene values. With `sum`, the pseudocount is added
+/// once to the summed counts. Confidence intervals and neutrality tests
+/// always use the summed counts, as do the extra counts. Watterson's θ and
+/// Tajima's D depend on the coverage of each gene, so they always use the
+/// per-gene values, `sum` is the mean.
+fn aggregate_group(
+    group: &GroupPnPs,
+    extra: Option<&HashMap<Uuid, ExtraCounts>>,
+    value_options: &ValueOptions,
+) -> f64 {
+    let gene_extra = |pnps: &PnPs| extra.and_then(|m| m.get(&pnps.uid));
+    if value_options.kind.uses_summed_counts()
+        || (value_options.aggregate == Aggregate::Sum && value_options.kind == ValueKind::Estimate)
+    {
+        let mut counts = Counts::default();
+        for pnps in group.pnps.iter() {
+            counts.add(&Counts::from_pnps(pnps).with_extra(gene_extra(pnps)));
+        }
+        return value_options.counts_value(&counts);
+    }
+
+    // value and coverage of each gene
+    let values: Vec<(f64, f64)> = group
+        .pnps
+        .iter()
+        .map(|pnps| {
+            (
+                value_options.gene_value(pnps, gene_extra(pnps)),
+                pnps.coverage as f64,
+            )
+        })
+        .filter(|(value, _)| value.is_finite())
+        .collect();
+    if values.is_empty() {
+        return f64::NAN;
+    }
+
+    match value_options.aggregate {
+        Aggregate::Mean | Aggregate::Sum => {
+            values.iter().map(|(v, _)| v).sum::<f64>() / values.len() as f64
+        }
+        Aggregate::Median => {
+            let mut values: Vec<f64> = values.iter().map(|(v, _)| *v).collect();
+            median(&mut values)
+        }
+        Aggregate::Weighted => {
+            let total: f64 = values.iter().map(|(_, w)| w).sum();
+            values.iter().map(|(v, w)| v * w).sum::<f64>() / total
+        }
+    }
+}
 
 fn group_pnps<'a>(
     pnps_map: &'a SamplePnPs,
     gene_map: &GeneMap,
     taxon_map: &TaxonMap,
     lineage_map: &LineageMap,
+    bin_map: &BinMap,
 ) -> SampleGroupPnPs<'a> {
+    // with only a bin map, all genes in a bin are pooled
+    let pool_bins = !bin_map.is_empty() && gene_map.is_empty();
     let mut grouped_pnps = SampleGroupPnPs::with_capacity(pnps_map.len());
     for (sample_id, pnps_values) in pnps_map.iter() {
-        let mut sample_map: HashMap<(String, u32, String), GroupPnPs<'a>> =
+        let mut sample_map: HashMap<GroupKey, GroupPnPs<'a>> =
             HashMap::with_capacity(pnps_values.len());
         for (uid, value) in pnps_values.iter() {
+            // genes on contigs without a bin are skipped
+            let bin_id = match bin_map.get(uid) {
+                None if !bin_map.is_empty() => continue,
+                None => "".to_string(),
+                Some(bin_id) => bin_id.clone(),
+            };
             // make Keys
             let gene_ids = match gene_map.get(uid) {
+                None if pool_bins => vec!["".to_string()],
                 None => vec![uid.to_string()],
                 Some(values) => values.clone(),
             };
@@ -49,7 +349,12 @@ fn group_pnps<'a>(
             };
             // Use Entry to insert/modify
             for gene_id in gene_ids {
-                let key = (gene_id.clone(), taxon_id, taxon_lineage.clone());
+                let key = (
+                    gene_id.clone(),
+                    taxon_id,
+                    taxon_lineage.clone(),
+                    bin_id.clone(),
+                );
                 sample_map
                     .entry(key)
                     .and_modify(|v| v.pnps.push(value))
@@ -67,15 +372,144 @@ fn group_pnps<'a>(
     grouped_pnps
 }
 
+/// Pools the samples in groups, summing the counts and expected sites of the
+/// annotations in the samples of each group. The coverage is the mean of the
+/// samples where the annotation passed the threshold.
+fn pool_samples(pnps_map: SamplePnPs, sample_groups: &HashMap<String, String>) -> SamplePnPs {
+    let mut pooled = SamplePnPs::new();
+    let mut counts: HashMap<(String, Uuid), u32> = HashMap::new();
+    for (sample_id, sample_map) in pnps_map {
+        let group = match sample_groups.get(&sample_id) {
+            None => continue,
+            Some(group) => group,
+        };
+        let group_map = pooled.entry(group.clone()).or_default();
+        for (uid, pnps) in sample_map {
+            *counts.entry((group.clone(), uid)).or_default() += 1;
+            match group_map.get_mut(&uid) {
+                None => {
+                    group_map.insert(uid, pnps);
+                }
+                Some(value) => {
+                    value.syn += pnps.syn;
+                    value.nonsyn += pnps.nonsyn;
+                    value.exp_syn += pnps.exp_syn;
+                    value.exp_nonsyn += pnps.exp_nonsyn;
+                    value.coverage += pnps.coverage;
+                }
+            }
+        }
+    }
+    for ((group, uid), count) in counts {
+        if let Some(value) = pooled.get_mut(&group).and_then(|m| m.get_mut(&uid)) {
+            value.coverage = (value.coverage as f64 / count as f64).round() as _;
+        }
+    }
+    pooled
+}
+
+/// Sums the extra counts of the samples in each group, as `pool_samples`
+fn pool_extra(extra: SampleExtra, sample_groups: &HashMap<String, String>) -> SampleExtra {
+    let mut pooled = SampleExtra::new();
+    for (sample_id, sample_map) in extra {
+        let group = match sample_groups.get(&sample_id) {
+            None => continue,
+            Some(group) => group,
+        };
+        let group_map = pooled.entry(group.clone()).or_default();
+        for (uid, counts) in sample_map {
+            group_map
+                .entry(uid)
+                .and_modify(|e| e.add(&counts))
+                .or_insert(counts);
+        }
+    }
+    pooled
+}
+
+/// Creates the CSV file, writing the comment lines before the header
+fn create_csv_writer<P: AsRef<Path>>(
+    file_name: P,
+    comments: &[String],
+) -> Result<csv::Writer<File>> {
+    let mut file_handle = File::create(file_name).context("Problem opening file")?;
+    for comment in comments {
+        writeln!(file_handle, "{}", comment).context("Problem writing comments")?;
+    }
+    Ok(csv::Writer::from_writer(file_handle))
+}
+
+/// Writes the provenance of the input file to a JSON file next to the output,
+/// with the `.provenance.json` suffix
+fn write_provenance_sidecar<P: AsRef<Path>>(file_name: P, provenance: &[Provenance]) -> Result<()> {
+    let mut sidecar_name = file_name.as_ref().as_os_str().to_owned();
+    sidecar_name.push(".provenance.json");
+    let sidecar_name = PathBuf::from(sidecar_name);
+    info!("Writing provenance to file {}", sidecar_name.display());
+    let file_handle = File::create(&sidecar_name).context("Problem opening provenance file")?;
+    serde_json::to_writer_pretty(file_handle, provenance)
+        .context("Problem writing provenance file")?;
+    Ok(())
+}
+
+/// Writes the rows to the CSV file, each row has the labels and the value for
+/// each sample. For q-values, the p-values of each sample are adjusted with
+/// Benjamini-Hochberg before writing.
+fn write_rows(
+    writer: &mut csv::Writer<File>,
+    mut rows: Vec<(Vec<String>, Vec<f64>)>,
+    value_options: &ValueOptions,
+) -> Result<()> {
+    if value_options.kind == ValueKind::QValue && !rows.is_empty() {
+        for column in 0..rows[0].1.len() {
+            let p_values: Vec<f64> = rows.iter().map(|(_, values)| values[column]).collect();
+            for (row, q_value) in rows.iter_mut().zip(benjamini_hochberg(&p_values)) {
+                row.1[column] = q_value;
+            }
+        }
+    }
+    for (mut record, values) in rows {
+        // only write the row if at least one value is defined, infinite ones
+        // included, they are written as `--inf-value`
+        if values.iter().any(|e| !e.is_nan()) {
+            record.extend(values.iter().map(|e| value_options.format_value(*e)));
+            writer
+                .write_record(&record)
+                .context("Problem writing Record")?;
+        }
+    }
+    Ok(())
+}
+
+/// Lineage of the group, from the lineage map or the taxonomy
+fn group_lineage(key: &GroupKey, taxonomy: &Taxonomy) -> Result<String> {
+    let (_, taxon_id, lineage, _) = key;
+    if !lineage.is_empty() {
+        Ok(lineage.clone())
+    } else if *taxon_id == 0 {
+        // no taxon information available
+        Ok("".to_string())
+    } else {
+        taxonomy
+            .get_taxon_lineage_string(taxon_id)
+            .context("Cannot build lineage string")
+    }
+}
+
+/// With `bins`, the bin and the number of genes contributing to the group in
+/// each sample (`n_genes:<sample>`) are written before the values
 fn write_grouped_output<P: AsRef<Path>>(
     file_name: P,
     pnps_map: &SampleGroupPnPs,
-    result_type: &ResultType,
+    extra: &SampleExtra,
+    value_options: &ValueOptions,
     taxonomy: &Taxonomy,
+    bins: bool,
+    comments: &[String],
 ) -> Result<()> {
     info!("Writing results to file {}", file_name.as_ref().display());
 
-    let mut non_null_index: HashSet<&(String, u32, String)> = HashSet::new();
+    let mut non_null_index: HashSet<&GroupKey> = HashSet::new();
 
     // prepares the index to be able to write records
     for map in pnps_map.values() {
@@ -84,12 +518,20 @@ fn write_grouped_output<P: AsRef<Path>>(
         }
     }
 
-    let mut writer = csv::Writer::from_path(file_name).context("Problem opening file")?;
-    let mut record = Vec::with_capacity(pnps_map.len() + 2);
+    let mut writer = create_csv_writer(file_name, comments)?;
+    let mut record = Vec::with_capacity(pnps_map.len() * 2 + 4);
 
     record.push("gene_id".to_string());
+    if bins {
+        record.push("bin".to_string());
+    }
     record.push("taxon".to_string());
     record.push("lineage".to_string());
+    if bins {
+        for sample_id in pnps_map.keys() {
+            record.push(format!("n_genes:{}", sample_id));
+        }
+    }
     for sample_id in pnps_map.keys() {
         record.push(sample_id.clone());
     }
@@ -97,52 +539,51 @@ fn write_grouped_output<P: AsRef<Path>>(
         .write_record(&record)
         .context("Problem writing Header")?;
 
-    for (gene_id, taxon_id, lineage) in non_null_index {
+    let mut rows: Vec<(Vec<String>, Vec<f64>)> = Vec::with_capacity(non_null_index.len());
+    for key in non_null_index {
+        let (gene_id, taxon_id, _, bin_id) = key;
         record.clear();
         record.push(gene_id.clone());
+        if bins {
+            record.push(bin_id.clone());
+        }
         record.push(taxon_id.to_string());
-        if lineage.is_empty() {
-            record.push(
-                taxonomy
-                    .get_taxon_lineage_string(taxon_id)
-                    .context("Cannot build lineage string")?,
-            );
-        } else {
-            record.push(lineage.clone());
+        record.push(group_lineage(key, taxonomy)?);
+        if bins {
+            for pmap in pnps_map.values() {
+                let n_genes = pmap.get(key).map(|g| g.pnps.len()).unwrap_or_default();
+                record.push(n_genes.to_string());
+            }
         }
         let mut values: Vec<f64> = Vec::with_capacity(pnps_map.len());
 
-        for pmap in pnps_map.values() {
-            let p = match pmap.get(&(gene_id.clone(), *taxon_id, lineage.clone())) {
+        for (sample_id, pmap) in pnps_map.iter() {
+            let p = match pmap.get(key) {
                 None => f64::NAN,
-                Some(value) => match result_type {
-                    ResultType::pNpS => value.get_pnps(),
-                    ResultType::pN => value.get_pn(),
-                    ResultType::pS => value.get_ps(),
-                },
+                Some(value) => aggregate_group(value, extra.get(sample_id), value_options),
             };
             // push value first
             values.push(p);
         }
-        // counts how many "normal", so no infinite, NAN are inside and
-        // only convert and write if at least one is there.
-        if values.iter().filter(|e| e.is_normal()).count() > 0 {
-            record.extend(values.iter().map(|e| e.to_string()));
-            writer
-                .write_record(&record)
-                .context("Problem writing Record")?;
-        }
+        rows.push((record.clone(), values));
     }
+    write_rows(&mut writer, rows, value_options)?;
 
     writer.flush().context("Problem flushing to disk")?;
 
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn write_output<P: AsRef<Path>>(
     file_name: P,
     pnps_map: &SamplePnPs,
-    result_type: &ResultType,
+    extra: &SampleExtra,
+    value_options: &ValueOptions,
+    annotations: &AnnotationTable,
+    label: &Option<LabelAttribute>,
+    add_position: bool,
+    comments: &[String],
 ) -> Result<()> {
     info!("Writing results to file {}", file_name.as_ref().display());
 
@@ -154,9 +595,18 @@ fn write_output<P: AsRef<Path>>(
         }
     }
 
-    let mut writer = csv::Writer::from_path(file_name).context("Problem opening file")?;
-    let mut record = Vec::with_capacity(pnps_map.len() + 1);
-    record.push("uid".to_string());
+    let mut writer = create_csv_writer(file_name, comments)?;
+    let mut record = Vec::with_capacity(pnps_map.len() + 5);
+    record.push(match label {
+        None => "uid".to_string(),
+        Some(label) => label.as_attribute().to_string(),
+    });
+    if add_position {
+        record.push("seq_id".to_string());
+        record.push("start".to_string());
+        record.push("end".to_string());
+        record.push("strand".to_string());
+    }
 
     for sample_id in pnps_map.keys() {
         record.push(sample_id.clone());
@@ -166,101 +616,313 @@ fn write_output<P: AsRef<Path>>(
         .write_record(&record)
         .context("Problem writing Header")?;
 
+    let mut rows: Vec<(Vec<String>, Vec<f64>)> = Vec::with_capacity(non_null_index.len());
     for uid in non_null_index {
         record.clear();
-        record.push(uid.to_string());
+        let annotation = annotations.get(&uid);
+        let row_label = match (label, annotation) {
+            (Some(label), Some(annotation)) => annotation.get_attribute(label.as_attribute()),
+            _ => None,
+        };
+        match row_label {
+            None => record.push(uid.to_string()),
+            Some(row_label) => record.push(row_label.clone()),
+        }
+        if add_position {
+            match annotation {
+                None => {
+                    warn!("No annotation information for {}", uid);
+                    record.extend(vec![String::new(); 4]);
+                }
+                Some(annotation) => {
+                    record.push(annotation.seq_id.clone());
+                    record.push(annotation.start.to_string());
+                    record.push(annotation.end.to_string());
+                    record.push(annotation.strand.clone());
+                }
+            }
+        }
         let mut values: Vec<f64> = Vec::with_capacity(pnps_map.len());
 
-        for pmap in pnps_map.values() {
+        for (sample_id, pmap) in pnps_map.iter() {
             let p = match pmap.get(&uid) {
                 None => f64::NAN,
-                Some(value) => match result_type {
-                    ResultType::pNpS => value.get_pnps(),
-                    ResultType::pN => value.get_pn(),
-                    ResultType::pS => value.get_ps(),
-                },
+                Some(value) => {
+                    value_options.gene_value(value, extra.get(sample_id).and_then(|m| m.get(&uid)))
+                }
             };
             // push value first
             values.push(p);
         }
-        // counts how many "normal", so no infinite, NAN are inside and
-        // only convert and write if at least one is there.
-        if values.iter().filter(|e| e.is_normal()).count() > 0 {
-            record.extend(values.iter().map(|e| e.to_string()));
+        rows.push((record.clone(), values));
+    }
+    write_rows(&mut writer, rows, value_options)?;
+
+    writer.flush().context("Problem flushing to disk")?;
+
+    Ok(())
+}
+
+/// Writes the folded site frequency spectrum, summed over the genes of each
+/// sample or, with map files, of each group in a sample. Each row has the
+/// number of synonymous or nonsynonymous sites in each bin of minor allele
+/// frequency, groups without sites are skipped.
+fn write_sfs<P: AsRef<Path>>(
+    file_name: P,
+    pnps_map: &SamplePnPs,
+    grouped_pnps: Option<&SampleGroupPnPs>,
+    extra: &SampleExtra,
+    taxonomy: &Taxonomy,
+    comments: &[String],
+) -> Result<()> {
+    // labels and the summed counts
+    let mut rows: Vec<(Vec<String>, ExtraCounts)> = vec![];
+    let mut sample_ids: Vec<&String> = pnps_map.keys().collect();
+    sample_ids.sort();
+    for sample_id in sample_ids {
+        let sample_extra = match extra.get(sample_id) {
+            None => continue,
+            Some(value) => value,
+        };
+        let groups = match grouped_pnps {
+            None => {
+                let mut total = ExtraCounts::default();
+                for uid in pnps_map[sample_id].keys() {
+                    if let Some(counts) = sample_extra.get(uid) {
+                        total.add(counts);
+                    }
+                }
+                rows.push((vec![sample_id.clone()], total));
+                continue;
+            }
+            Some(grouped_pnps) => match grouped_pnps.get(sample_id) {
+                None => continue,
+                Some(value) => value,
+            },
+        };
+        let mut keys: Vec<&GroupKey> = groups.keys().collect();
+        keys.sort();
+        for key in keys {
+            let mut total = ExtraCounts::default();
+            for pnps in groups[key].pnps.iter() {
+                if let Some(counts) = sample_extra.get(&pnps.uid) {
+                    total.add(counts);
+                }
+            }
+            let (gene_id, taxon_id, _, bin_id) = key;
+            let labels = vec![
+                sample_id.clone(),
+                gene_id.clone(),
+                bin_id.clone(),
+                taxon_id.to_string(),
+                group_lineage(key, taxonomy)?,
+            ];
+            rows.push((labels, total));
+        }
+    }
+
+    let n_bins = rows
+        .iter()
+        .map(|(_, counts)| counts.sfs_syn.len().max(counts.sfs_nonsyn.len()))
+        .max()
+        .unwrap_or_default();
+    if n_bins == 0 {
+        warn!("The input file has no site frequency spectrum, use `--sfs-bins` with `parse`");
+        return Ok(());
+    }
+
+    info!(
+        "Writing site frequency spectrum to file {}",
+        file_name.as_ref().display()
+    );
+    let mut writer = create_csv_writer(file_name, comments)?;
+    let mut record: Vec<String> = vec!["sample".to_string()];
+    if grouped_pnps.is_some() {
+        for label in ["gene_id", "bin", "taxon", "lineage"] {
+            record.push(label.to_string());
+        }
+    }
+    record.push("class".to_string());
+    let width = 0.5 / n_bins as f64;
+    for bin in 0..n_bins {
+        record.push(format!(
+            "{:.3}-{:.3}",
+            bin as f64 * width,
+            (bin + 1) as f64 * width
+        ));
+    }
+    writer
+        .write_record(&record)
+        .context("Problem writing Header")?;
+
+    for (labels, counts) in rows {
+        if counts
+            .sfs_syn
+            .iter()
+            .chain(counts.sfs_nonsyn.iter())
+            .all(|c| *c == 0)
+        {
+            continue;
+        }
+        for (class, sfs) in [("syn", &counts.sfs_syn), ("nonsyn", &counts.sfs_nonsyn)] {
+            record.clear();
+            record.extend(labels.iter().cloned());
+            record.push(class.to_string());
+            record.extend((0..n_bins).map(|bin| sfs.get(bin).copied().unwrap_or(0).to_string()));
             writer
                 .write_record(&record)
                 .context("Problem writing Record")?;
         }
     }
-
     writer.flush().context("Problem flushing to disk")?;
 
     Ok(())
 }
 
-fn read_gene_map_file<P: AsRef<Path>>(file_name: P) -> Result<GeneMap> {
+pub fn read_gene_map_file<P: AsRef<Path>>(file_name: P, resolver: &KeyResolver) -> Result<GeneMap> {
     info!("Reading Gene map file: {}", &file_name.as_ref().display());
     let file_handle = bio_rascal::io::open_file(file_name).context("Cannot open file")?;
 
     let mut gene_map = GeneMap::new();
+    let mut not_found = 0u32;
 
     for line in file_handle.lines() {
         let line = line.context("Cannot parse line in map file")?;
         if line.starts_with('#') || line.is_empty() {
             continue;
         }
-        if let Some((uid, values)) = line.trim().split_once('\t') {
-            let uid = Uuid::from_str(uid).context("Cannot parse UID in line")?;
+        if let Some((key, values)) = line.trim().split_once('\t') {
+            let uids = resolver.resolve(key)?;
+            if uids.is_empty() {
+                not_found += 1;
+                continue;
+            }
             let values: Vec<String> = values.split(',').map(|s| s.to_string()).collect();
-            gene_map.insert(uid, values);
+            for uid in uids {
+                gene_map.insert(uid, values.clone());
+            }
         }
     }
+    if not_found > 0 {
+        warn!("Keys not found in the annotations: {}", not_found);
+    }
     Ok(gene_map)
 }
 
-fn read_taxon_map_file<P: AsRef<Path>>(file_name: P) -> Result<TaxonMap> {
+fn read_taxon_map_file<P: AsRef<Path>>(file_name: P, resolver: &KeyResolver) -> Result<TaxonMap> {
     info!("Reading Taxon map file: {}", &file_name.as_ref().display());
     let file_handle = bio_rascal::io::open_file(file_name).context("Cannot open file")?;
     let mut taxon_map = TaxonMap::new();
+    let mut not_found = 0u32;
 
     for line in file_handle.lines() {
         let line = line.context("Problem parsing line")?;
         if line.starts_with('#') || line.is_empty() {
             continue;
         }
-        if let Some((uid, taxon_id)) = line.trim().split_once('\t') {
-            let uid = Uuid::from_str(uid).context("Cannot parse UID in line")?;
+        if let Some((key, taxon_id)) = line.trim().split_once('\t') {
+            let uids = resolver.resolve(key)?;
+            if uids.is_empty() {
+                not_found += 1;
+                continue;
+            }
             let taxon_id = u32::from_str(taxon_id).context("Cannot convert taxon ID")?;
-            taxon_map.insert(uid, taxon_id);
+            for uid in uids {
+                taxon_map.insert(uid, taxon_id);
+            }
         }
     }
+    if not_found > 0 {
+        warn!("Keys not found in the annotations: {}", not_found);
+    }
 
     Ok(taxon_map)
 }
 
-fn read_lineage_map_file<P: AsRef<Path>>(file_name: P) -> Result<LineageMap> {
+fn read_lineage_map_file<P: AsRef<Path>>(
+    file_name: P,
+    resolver: &KeyResolver,
+) -> Result<LineageMap> {
     info!("Reading Taxon map file: {}", &file_name.as_ref().display());
     let file_handle = bio_rascal::io::open_file(file_name).context("Cannot open file")?;
     let mut lineage_map = LineageMap::new();
+    let mut not_found = 0u32;
 
     for line in file_handle.lines() {
         let line = line.context("Problem parsing line")?;
         if line.starts_with('#') || line.is_empty() {
             continue;
         }
-        if let Some((uid, lineage)) = line.trim().split_once('\t') {
-            let uid = Uuid::from_str(uid).context("Cannot parse UID in line")?;
-            lineage_map.insert(uid, lineage.to_string());
+        if let Some((key, lineage)) = line.trim().split_once('\t') {
+            let uids = resolver.resolve(key)?;
+            if uids.is_empty() {
+                not_found += 1;
+                continue;
+            }
+            for uid in uids {
+                lineage_map.insert(uid, lineage.to_string());
+            }
         }
     }
+    if not_found > 0 {
+        warn!("Keys not found in the annotations: {}", not_found);
+    }
 
     Ok(lineage_map)
 }
 
+fn read_bin_map_file<P: AsRef<Path>>(file_name: P, resolver: &KeyResolver) -> Result<BinMap> {
+    info!("Reading Bin map file: {}", &file_name.as_ref().display());
+    let file_handle = bio_rascal::io::open_file(file_name).context("Cannot open file")?;
+    let mut bin_map = BinMap::new();
+    let mut not_found = 0u32;
+
+    for line in file_handle.lines() {
+        let line = line.context("Problem parsing line")?;
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        if let Some((contig, bin_id)) = line.trim().split_once('\t') {
+            let uids = resolver.resolve(contig)?;
+            if uids.is_empty() {
+                not_found += 1;
+                continue;
+            }
+            for uid in uids {
+                bin_map.insert(uid, bin_id.to_string());
+            }
+        }
+    }
+    if not_found > 0 {
+        warn!("Contigs without annotations: {}", not_found);
+    }
+
+    Ok(bin_map)
+}
+
 pub fn calc_command(options: super::cli::Calc) -> Result<()> {
+    info!(
+        "Reading pN/pS data from file: {}",
+        options.input_file.display()
+    );
+    let parse_output = read_parse_output(&options.input_file)?;
+    let mut pnps_map = parse_output.samples;
+    let mut extra = parse_output.extra;
+    if let (Some(metadata_file), Some(column)) = (options.sample_metadata, options.group_samples_by)
+    {
+        let metadata = SampleMetadata::read_from_file(metadata_file)?;
+        let sample_groups = metadata.get_groups(&column, pnps_map.keys())?;
+        pnps_map = pool_samples(pnps_map, &sample_groups);
+        extra = pool_extra(extra, &sample_groups);
+        info!("Samples pooled by {} in {} groups", column, pnps_map.len());
+    }
+
+    info!("Keys used in map files: {}", options.map_key);
+    let resolver = KeyResolver::new(options.map_key, &parse_output.annotations);
+
     let mut gene_map = GeneMap::new();
     if let Some(gene_map_file) = options.gene_map {
-        gene_map = read_gene_map_file(gene_map_file)?;
+        gene_map = read_gene_map_file(gene_map_file, &resolver)?;
     }
 
     let mut taxonomy = Taxonomy::default();
@@ -271,27 +933,27 @@ pub fn calc_command(options: super::cli::Calc) -> Result<()> {
 
     let mut taxon_map = TaxonMap::new();
     if let Some(taxon_map_file) = options.taxon_map {
-        taxon_map = read_taxon_map_file(taxon_map_file)?;
+        taxon_map = read_taxon_map_file(taxon_map_file, &resolver)?;
     }
 
     let mut lineage_map = LineageMap::new();
     if let Some(lineage_map_file) = options.lineage_map {
-        lineage_map = read_lineage_map_file(lineage_map_file)?;
+        lineage_map = read_lineage_map_file(lineage_map_file, &resolver)?;
+    }
+
+    let mut bin_map = BinMap::new();
+    if let Some(bin_map_file) = options.bin_map {
+        // bin maps are always keyed by contig
+        let contig_resolver = KeyResolver::new(MapKey::Contig, &parse_output.annotations);
+        bin_map = read_bin_map_file(bin_map_file, &contig_resolver)?;
+        info!("Number of binned annotations: {}", bin_map.len());
     }
 
     if let Some(taxon_rank) = options.taxon_rank {
         warn!("Using a rank is not implemented, passed: {}", taxon_rank);
     }
 
-    info!(
-        "Reading pN/pS data from file: {}",
-        options.input_file.display()
-    );
-    let pnps_file = bio_rascal::io::open_file_base(&options.input_file)
-        .context("Cannot open the input file")?;
-    let pnps_map: SamplePnPs =
-        serde_json::from_reader(pnps_file).context("Problem parsing the input file")?;
-
+    info!("Aggregation method for groups: {}", options.aggregate);
     let result_type = match (options.output_pn, options.output_ps) {
         (true, false) => {
             info!("Calculating pN");
@@ -307,19 +969,439 @@ pub fn calc_command(options: super::cli::Calc) -> Result<()> {
         }
     };
 
-    if taxon_map.is_empty() && gene_map.is_empty() && lineage_map.is_empty() {
-        write_output(&options.output_file, &pnps_map, &result_type)
-            .context("Problem writing output file")?;
+    if options.pseudocount > 0. {
+        info!("Pseudocount for syn/nonsyn counts: {}", options.pseudocount);
+    }
+    let bootstrap = match options.bootstrap {
+        0 => None,
+        replicates => {
+            info!(
+                "Bootstrap confidence intervals ({}), {} replicates, seed {}",
+                options.confidence, replicates, options.seed
+            );
+            if options.aggregate != Aggregate::Sum {
+                warn!("Confidence intervals for groups use the summed counts");
+            }
+            Some(Bootstrap {
+                replicates,
+                seed: options.seed,
+                confidence: options.confidence,
+                intervals: Rc::default(),
+            })
+        }
+    };
+    let value_options = ValueOptions {
+        result_type,
+        aggregate: options.aggregate,
+        kind: ValueKind::Estimate,
+        bootstrap,
+        neutrality_test: options.neutrality_test,
+        pseudocount: options.pseudocount,
+        inf_value: options.inf_value,
+        nan_value: options.nan_value,
+    };
+
+    let mut comments: Vec<String> = vec![];
+    match options.provenance {
+        ProvenanceOutput::Comments => {
+            for provenance in parse_output.provenance.iter() {
+                comments.extend(provenance.to_comment_lines());
+            }
+        }
+        ProvenanceOutput::Sidecar => {
+            write_provenance_sidecar(&options.output_file, &parse_output.provenance)?
+        }
+        ProvenanceOutput::None => {}
+    }
+
+    // the estimate and, with bootstrap, the bounds of the confidence interval
+    let mut outputs = vec![(options.output_file.clone(), value_options.clone())];
+    if value_options.bootstrap.is_some() {
+        for (kind, extension) in [
+            (ValueKind::Lower, "lower.csv"),
+            (ValueKind::Upper, "upper.csv"),
+        ] {
+            outputs.push((
+                options.output_file.with_extension(extension),
+                ValueOptions {
+                    kind,
+                    ..value_options.clone()
+                },
+            ));
+        }
+    }
+
+    if let Some(test) = options.neutrality_test {
+        info!("Neutrality test: {:?}", test);
+        for (kind, extension) in [
+            (ValueKind::PValue, "pvalue.csv"),
+            (ValueKind::QValue, "qvalue.csv"),
+        ] {
+            outputs.push((
+                options.output_file.with_extension(extension),
+                ValueOptions {
+                    kind,
+                    ..value_options.clone()
+                },
+            ));
+        }
+    }
+
+    if options.stop_codons {
+        if extra.is_empty() {
+            warn!("The input file has no stop-gain and stop-loss counts");
+        }
+        for (kind, extension) in [
+            (ValueKind::StopGain, "stop_gain.csv"),
+            (ValueKind::StopLoss, "stop_loss.csv"),
+        ] {
+            outputs.push((
+                options.output_file.with_extension(extension),
+                ValueOptions {
+                    kind,
+                    ..value_options.clone()
+                },
+            ));
+        }
+    }
+
+    if options.indels {
+        if extra.is_empty() {
+            warn!("The input file has no indel counts");
+        }
+        for (kind, extension) in [
+            (ValueKind::InframeIndel, "inframe.csv"),
+            (ValueKind::FrameshiftIndel, "frameshift.csv"),
+        ] {
+            outputs.push((
+                options.output_file.with_extension(extension),
+                ValueOptions {
+                    kind,
+                    ..value_options.clone()
+                },
+            ));
+        }
+    }
+
+    if options.diversity {
+        if extra.is_empty() {
+            warn!("The input file has no nucleotide diversity");
+        }
+        for (kind, extension) in [(ValueKind::Pi, "pi.csv"), (ValueKind::PiNPiS, "pinpis.csv")] {
+            outputs.push((
+                options.output_file.with_extension(extension),
+                ValueOptions {
+                    kind,
+                    ..value_options.clone()
+                },
+            ));
+        }
+    }
+
+    if options.population_stats {
+        if extra.is_empty() {
+            warn!("The input file has no segregating sites");
+        }
+        for (kind, extension) in [
+            (ValueKind::WattersonTheta, "theta.csv"),
+            (ValueKind::TajimaD, "tajima_d.csv"),
+        ] {
+            outputs.push((
+                options.output_file.with_extension(extension),
+                ValueOptions {
+                    kind,
+                    ..value_options.clone()
+                },
+            ));
+        }
+    }
+
+    let use_maps = !(taxon_map.is_empty()
+        && gene_map.is_empty()
+        && lineage_map.is_empty()
+        && bin_map.is_empty());
+    let grouped_pnps = if use_maps {
+        group_pnps(&pnps_map, &gene_map, &taxon_map, &lineage_map, &bin_map)
     } else {
-        let grouped_pnps = group_pnps(&pnps_map, &gene_map, &taxon_map, &lineage_map);
-        write_grouped_output(
-            &options.output_file,
-            &grouped_pnps,
-            &result_type,
+        SampleGroupPnPs::new()
+    };
+
+    if options.sfs {
+        write_sfs(
+            options.output_file.with_extension("sfs.csv"),
+            &pnps_map,
+            use_maps.then_some(&grouped_pnps),
+            &extra,
             &taxonomy,
+            &comments,
         )
-        .context("Problem writing output file")?;
+        .context("Problem writing site frequency spectrum")?;
+    }
+
+    for (output_file, value_options) in outputs {
+        if use_maps {
+            write_grouped_output(
+                &output_file,
+                &grouped_pnps,
+                &extra,
+                &value_options,
+                &taxonomy,
+                !bin_map.is_empty(),
+                &comments,
+            )
+            .context("Problem writing output file")?;
+        } else {
+            write_output(
+                &output_file,
+                &pnps_map,
+                &extra,
+                &value_options,
+                &parse_output.annotations,
+                &options.label,
+                options.add_position,
+                &comments,
+            )
+            .context("Problem writing output file")?;
+        }
     }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn counts(syn: f64, nonsyn: f64) -> Counts {
+        Counts {
+            syn,
+            nonsyn,
+            exp_syn: 25.,
+            exp_nonsyn: 75.,
+            ..Default::default()
+        }
+    }
+
+    fn pnps(id: u128, syn: u32, nonsyn: u32, coverage: u32) -> PnPs {
+        PnPs {
+            uid: Uuid::from_u128(id),
+            exp_syn: 25.,
+            exp_nonsyn: 75.,
+            syn,
+            nonsyn,
+            coverage,
+            ..Default::default()
+        }
+    }
+
+    fn group(pnps: &[PnPs]) -> GroupPnPs<'_> {
+        GroupPnPs {
+            gene_id: "gene".to_string(),
+            taxon_id: 0,
+            pnps: pnps.iter().collect(),
+            taxon_lineage: "".to_string(),
+        }
+    }
+
+    fn value_options(kind: ValueKind, aggregate: Aggregate) -> ValueOptions {
+        ValueOptions {
+            result_type: ResultType::pNpS,
+            aggregate,
+            kind,
+            bootstrap: None,
+            neutrality_test: None,
+            pseudocount: 0.,
+            inf_value: "inf".to_string(),
+            nan_value: "NaN".to_string(),
+        }
+    }
+
+    #[test]
+    fn test_aggregate_group() {
+        // pN/pS of 1, 3 and 1, coverage 10, 30 and 20
+        let values = [pnps(1, 2, 6, 10), pnps(2, 1, 9, 30), pnps(3, 1, 3, 20)];
+        let group = group(&values);
+        let aggregate = |aggregate| {
+            aggregate_group(&group, None, &value_options(ValueKind::Estimate, aggregate))
+        };
+        // 18 nonsynonymous and 4 synonymous SNPs
+        assert_eq!(aggregate(Aggregate::Sum), 1.5);
+        assert!((aggregate(Aggregate::Mean) - 5. / 3.).abs() < 1e-12);
+        assert_eq!(aggregate(Aggregate::Median), 1.);
+        assert_eq!(aggregate(Aggregate::Weighted), 2.);
+        // the pseudocount is added once to the summed counts
+        let options = ValueOptions {
+            pseudocount: 1.,
+            ..value_options(ValueKind::Estimate, Aggregate::Sum)
+        };
+        assert!((aggregate_group(&group, None, &options) - 19. / 15.).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_aggregate_group_not_finite() {
+        // infinite (no synonymous SNPs) and undefined (no SNPs) pN/pS
+        let values = [pnps(1, 2, 6, 10), pnps(2, 0, 3, 30), pnps(3, 0, 0, 20)];
+        let group = group(&values);
+        for aggregate in [Aggregate::Mean, Aggregate::Median, Aggregate::Weighted] {
+            let options = value_options(ValueKind::Estimate, aggregate);
+            assert_eq!(aggregate_group(&group, None, &options), 1.);
+        }
+        let options = value_options(ValueKind::Estimate, Aggregate::Sum);
+        assert_eq!(aggregate_group(&group, None, &options), 1.5);
+        // no gene with a finite value
+        let group = GroupPnPs {
+            pnps: group.pnps[1..].to_vec(),
+            ..group
+        };
+        let options = value_options(ValueKind::Estimate, Aggregate::Mean);
+        assert!(aggregate_group(&group, None, &options).is_nan());
+    }
+
+    #[test]
+    fn test_aggregate_group_summed_counts() {
+        let values = [pnps(1, 2, 6, 2), pnps(2, 1, 9, 3)];
+        let group = group(&values);
+        let extra = HashMap::from([
+            (
+                values[0].uid,
+                ExtraCounts {
+                    stop_gain: 1,
+                    pi: 2.,
+                    segregating_sites: 4,
+                    ..Default::default()
+                },
+            ),
+            (
+                values[1].uid,
+                ExtraCounts {
+                    stop_gain: 2,
+                    pi: 0.,
+                    segregating_sites: 3,
+                    ..Default::default()
+                },
+            ),
+        ]);
+        // the extra counts and π are from the summed counts, whatever the
+        // aggregation method
+        for aggregate in [Aggregate::Sum, Aggregate::Median, Aggregate::Weighted] {
+            let options = value_options(ValueKind::StopGain, aggregate);
+            assert_eq!(aggregate_group(&group, Some(&extra), &options), 3.);
+            let options = value_options(ValueKind::Pi, aggregate);
+            assert_eq!(aggregate_group(&group, Some(&extra), &options), 0.01);
+        }
+        // Watterson's θ is the mean of the genes, 4 / 1 and 3 / 1.5 per 100
+        // sites, even with `sum`
+        for aggregate in [Aggregate::Sum, Aggregate::Mean] {
+            let options = value_options(ValueKind::WattersonTheta, aggregate);
+            assert_eq!(aggregate_group(&group, Some(&extra), &options), 0.03);
+        }
+        // without extra counts
+        let options = value_options(ValueKind::StopGain, Aggregate::Sum);
+        assert!(aggregate_group(&group, None, &options).is_nan());
+    }
+
+    #[test]
+    fn test_pool_samples() {
+        let sample_groups = HashMap::from([
+            ("S1".to_string(), "G1".to_string()),
+            ("S2".to_string(), "G1".to_string()),
+            ("S3".to_string(), "G2".to_string()),
+        ]);
+        // S4 is not in a group, the gene 2 is only in S2 for G1
+        let pnps_map = SamplePnPs::from([
+            (
+                "S1".to_string(),
+                HashMap::from([(Uuid::from_u128(1), pnps(1, 2, 6, 10))]),
+            ),
+            (
+                "S2".to_string(),
+                HashMap::from([
+                    (Uuid::from_u128(1), pnps(1, 1, 9, 15)),
+                    (Uuid::from_u128(2), pnps(2, 1, 3, 20)),
+                ]),
+            ),
+            (
+                "S3".to_string(),
+                HashMap::from([(Uuid::from_u128(1), pnps(1, 4, 4, 8))]),
+            ),
+            (
+                "S4".to_string(),
+                HashMap::from([(Uuid::from_u128(1), pnps(1, 1, 1, 8))]),
+            ),
+        ]);
+        let pooled = pool_samples(pnps_map, &sample_groups);
+        assert_eq!(pooled.len(), 2);
+        let gene = &pooled["G1"][&Uuid::from_u128(1)];
+        assert_eq!((gene.syn, gene.nonsyn), (3, 15));
+        assert_eq!((gene.exp_syn, gene.exp_nonsyn), (50., 150.));
+        // mean of 10 and 15, rounded
+        assert_eq!(gene.coverage, 13);
+        let gene = &pooled["G1"][&Uuid::from_u128(2)];
+        assert_eq!((gene.syn, gene.nonsyn, gene.coverage), (1, 3, 20));
+        let gene = &pooled["G2"][&Uuid::from_u128(1)];
+        assert_eq!((gene.syn, gene.nonsyn, gene.coverage), (4, 4, 8));
+
+        let extra = SampleExtra::from([
+            (
+                "S1".to_string(),
+                HashMap::from([(
+                    Uuid::from_u128(1),
+                    ExtraCounts {
+                        stop_gain: 1,
+                        pi: 0.5,
+                        sfs_syn: vec![1, 2],
+                        ..Default::default()
+                    },
+                )]),
+            ),
+            (
+                "S2".to_string(),
+                HashMap::from([(
+                    Uuid::from_u128(1),
+                    ExtraCounts {
+                        stop_gain: 2,
+                        frameshift_indel: 1,
+                        pi: 0.25,
+                        sfs_syn: vec![0, 1, 1],
+                        ..Default::default()
+                    },
+                )]),
+            ),
+            ("S4".to_string(), HashMap::new()),
+        ]);
+        let pooled = pool_extra(extra, &sample_groups);
+        assert_eq!(pooled.len(), 1);
+        let counts = &pooled["G1"][&Uuid::from_u128(1)];
+        assert_eq!((counts.stop_gain, counts.frameshift_indel), (3, 1));
+        assert_eq!(counts.pi, 0.75);
+        assert_eq!(counts.sfs_syn, [1, 3, 1]);
+    }
+
+    #[test]
+    fn test_bootstrap_interval() {
+        let bootstrap = Bootstrap {
+            replicates: 200,
+            seed: 42,
+            confidence: 0.95,
+            intervals: Rc::default(),
+        };
+        // no synonymous SNPs, all replicates are infinite
+        let (lower, upper) = bootstrap.interval(&counts(0., 5.), 0., &ResultType::pNpS);
+        assert_eq!((lower, upper), (f64::INFINITY, f64::INFINITY));
+        // a few replicates without synonymous SNPs
+        let (lower, upper) = bootstrap.interval(&counts(1., 10.), 0., &ResultType::pNpS);
+        assert!(lower.is_finite() && lower > 0.);
+        assert_eq!(upper, f64::INFINITY);
+        // same seed and counts
+        let other = Bootstrap {
+            intervals: Rc::default(),
+            ..bootstrap.clone()
+        };
+        let interval = other.interval(&counts(10., 20.), 0., &ResultType::pNpS);
+        assert_eq!(
+            bootstrap.interval(&counts(10., 20.), 0., &ResultType::pNpS),
+            interval
+        );
+        assert!(interval.0 < interval.1);
+    }
+}
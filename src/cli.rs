@@ -1,4 +1,4 @@
-use clap::{Args, Command, Parser, Subcommand};
+use clap::{Args, Command, Parser, Subcommand, ValueEnum};
 use clap_complete::{generate, Generator, Shell};
 use std::path::PathBuf;
 
@@ -23,6 +23,11 @@ pub enum Commands {
     Config(Config),
     Parse(Parse),
     Calc(Calc),
+    Merge(Merge),
+    Subset(Subset),
+    #[command(alias = "stats")]
+    Inspect(Inspect),
+    Compare(Compare),
 }
 
 /// Generates the config file for command `parse`
@@ -79,11 +84,83 @@ pub struct Parse {
     #[arg(short = 'a', long, default_value_t = 4, value_parser = clap::value_parser!(u32).range(1..=20))]
     pub min_coverage: u32,
     /// Minimum Quality `QUAL` in VCF file
+    ///
+    /// Records without `QUAL` (`.`) are skipped, unless this is 0
     #[arg(short = 'q', long, default_value_t = 30.)]
     pub min_qual: f64,
+    /// Writes a table with the details of each SNP counted
+    ///
+    /// Tab separated, with sample, position, alleles, gene, codon and
+    /// amino acid change, class, depth and allele frequency. The codon
+    /// and class are for the single SNP, even if other SNPs are in the
+    /// same codon. The file is compressed if the name ends in `.gz` or
+    /// `.zst`.
+    #[arg(long)]
+    pub snp_table: Option<PathBuf>,
+    /// Writes the VCF file with the effect of the SNPs in CDS
+    ///
+    /// `INFO` fields are added with the CDS UID and locus tag, codon and
+    /// amino acid change and the class (SYN, NONSYN, STOP_GAIN or
+    /// STOP_LOSS), one value for each alternative allele and CDS, the
+    /// allele is in `PNPS_ALT`. Records that are filtered out are written
+    /// without annotations.
+    #[arg(long)]
+    pub annotated_vcf: Option<PathBuf>,
+    /// Averages the SNPs in the same codon over the mutation pathways
+    ///
+    /// SNPs of a sample in the same codon are classified by the change of
+    /// the whole codon. With this option, the synonymous and
+    /// nonsynonymous counts are averaged over all the orders in which the
+    /// mutations may have happened (Nei-Gojobori), excluding pathways
+    /// through stop codons, so they can be fractional.
+    #[arg(long)]
+    pub pathway_averaging: bool,
+    /// Weights the expected sites by the transition/transversion ratio
+    ///
+    /// Either a value for κ or `estimate`, to estimate it for each sample
+    /// from its SNPs (as twice the ratio of transitions to transversions,
+    /// since each base has one possible transition and two
+    /// transversions). By default all substitutions are equally likely,
+    /// which inflates the nonsynonymous sites with a transition bias.
+    ///
+    /// The weighted sites are computed with the standard genetic code,
+    /// counting changes to a stop codon as nonsynonymous, so they can
+    /// differ from the default ones even with a κ of 1.
+    #[arg(long, value_parser = parse_kappa)]
+    pub kappa: Option<Kappa>,
+    /// Saves the folded site frequency spectrum with this number of bins
+    ///
+    /// The minor allele frequencies, from 0 to 0.5, of the sites in each
+    /// CDS are split in bins of the same width, for synonymous and
+    /// nonsynonymous sites. The allele depths (`AD`) are needed. Use the
+    /// `--sfs` option of `calc` to export it. At most 20 bins, as the
+    /// spectrum is stored for each CDS and sample.
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..=20))]
+    pub sfs_bins: Option<u16>,
+    /// Writes the mutation spectrum of each sample
+    ///
+    /// Tab separated, with the SNPs of each sample counted in the six
+    /// substitution classes (C>A, C>G, C>T, T>A, T>C, T>G), collapsing
+    /// the strands, and separately for coding and non-coding regions. The
+    /// file is compressed if the name ends in `.gz` or `.zst`.
+    #[arg(long)]
+    pub mutation_spectrum: Option<PathBuf>,
+    /// Uses the trinucleotide context in the mutation spectrum
+    ///
+    /// The bases before and after each SNP are taken from the Fasta file,
+    /// giving 96 classes (e.g. `A[C>T]G`).
+    #[arg(long, requires = "mutation_spectrum")]
+    pub spectrum_context: bool,
     /// VCF file with SNPs
     pub vcf_file: PathBuf,
-    /// file name for the output, defaults to `pnps.json.gz`
+    /// file name for the output, defaults to `pnps.bin`
+    ///
+    /// The binary format is used, unless the file name ends in `.json`,
+    /// `.json.gz` or `.json.zst`, which export to JSON. The compression
+    /// is chosen from the extension, `.gz` for gzip and `.zst` for zstd.
+    /// The binary format is not compressed, so other names ending in
+    /// `.gz` or `.zst` are rejected. Older versions wrote JSON to
+    /// `pnps.json.gz` by default, pass it to keep that output.
     pub output_file: Option<PathBuf>,
 }
 
@@ -105,6 +182,120 @@ pub struct Calc {
     /// Alternative to `--taxon_map` and the map contains strings showing the full lineage
     #[arg(short = 'l', long, group = "lineage")]
     pub lineage_map: Option<PathBuf>,
+    /// Bin map, mapping a contig to a bin (e.g. MAG)
+    ///
+    /// All annotations on the contigs of a bin are grouped together,
+    /// unless a gene map is also passed, in which case the groups are
+    /// by bin and gene ID. Annotations on contigs not in the map are
+    /// skipped. The output has a `bin` column and, for each sample, the
+    /// number of genes in the group (`n_genes:<sample>`).
+    #[arg(long)]
+    pub bin_map: Option<PathBuf>,
+    /// Pseudocount added to synonymous and nonsynonymous counts
+    ///
+    /// Avoids infinite pN/pS for genes with no synonymous SNPs
+    #[arg(long, default_value_t = 0., value_parser = parse_non_negative)]
+    pub pseudocount: f64,
+    /// Value written for infinite values, e.g. when pS = 0
+    #[arg(long, default_value = "Inf")]
+    pub inf_value: String,
+    /// Value written for undefined or missing values
+    ///
+    /// An empty string can be passed, to leave the cell empty
+    #[arg(long, default_value = "NA")]
+    pub nan_value: String,
+    /// Number of bootstrap replicates for confidence intervals, 0 to
+    /// disable
+    ///
+    /// The SNPs of each gene (or group) are resampled with replacement.
+    /// The lower and upper bounds are written to two more files, with
+    /// the `.lower.csv` and `.upper.csv` extensions.
+    #[arg(long, default_value_t = 0)]
+    pub bootstrap: u32,
+    /// Seed used for the bootstrap
+    #[arg(long, default_value_t = 42)]
+    pub seed: u64,
+    /// Confidence level of the bootstrap intervals
+    #[arg(long, default_value_t = 0.95, value_parser = parse_fraction)]
+    pub confidence: f64,
+    /// Tests each value against neutrality (pN/pS = 1)
+    ///
+    /// The p-values and the q-values (Benjamini-Hochberg, for each
+    /// sample) are written to two more files, with the `.pvalue.csv`
+    /// and `.qvalue.csv` extensions.
+    #[arg(long, value_enum)]
+    pub neutrality_test: Option<NeutralityTest>,
+    /// Writes the number of stop-gain and stop-loss SNPs
+    ///
+    /// The counts are written to two more files, with the
+    /// `.stop_gain.csv` and `.stop_loss.csv` extensions. Groups use the
+    /// sum of the counts.
+    #[arg(long)]
+    pub stop_codons: bool,
+    /// Writes the number of in-frame and frameshift indels
+    ///
+    /// The counts are written to two more files, with the `.inframe.csv`
+    /// and `.frameshift.csv` extensions. Groups use the sum of the counts.
+    #[arg(long)]
+    pub indels: bool,
+    /// Writes the nucleotide diversity (π) and πN/πS
+    ///
+    /// The values are written to two more files, with the `.pi.csv` and
+    /// `.pinpis.csv` extensions. π is per site, using the expected sites
+    /// as the number of sites, πN and πS use the nonsynonymous and
+    /// synonymous sites. Groups sum the diversity and the sites.
+    #[arg(long)]
+    pub diversity: bool,
+    /// Writes Watterson's θ and Tajima's D
+    ///
+    /// The values are written to two more files, with the `.theta.csv`
+    /// and `.tajima_d.csv` extensions. The segregating sites are the ones
+    /// with more than one allele in the sample and the sample size is the
+    /// mean coverage of the gene. θ is per site, using the expected sites.
+    /// Groups use the per-gene values, `sum` is the same as `mean`. Not
+    /// available when pooling samples, as the statistics of the pooled
+    /// samples cannot be combined.
+    #[arg(long, conflicts_with = "group_samples_by")]
+    pub population_stats: bool,
+    /// Writes the folded site frequency spectrum
+    ///
+    /// The spectrum saved by `parse` with `--sfs-bins` is written to a
+    /// file with the `.sfs.csv` extension, summed over the genes of each
+    /// sample, or of each group when map files are used. There are two
+    /// rows for each sample or group, for synonymous and nonsynonymous
+    /// sites, with the number of sites in each bin of minor allele
+    /// frequency.
+    #[arg(long)]
+    pub sfs: bool,
+    /// How the values of the genes in a group are combined
+    ///
+    /// `sum` uses the ratio of summed counts, `mean` and `median`
+    /// the per-gene values and `weighted` the mean of per-gene values
+    /// weighted by the gene coverage. Only used with maps.
+    #[arg(long, value_enum, default_value_t = Aggregate::Sum)]
+    pub aggregate: Aggregate,
+    /// Sample metadata, `tab` separated with a header
+    ///
+    /// The first column is the sample ID, its header must be `sample`,
+    /// `sample_id`, `sample_name` or `id` (any case, can start with `#`).
+    /// The other columns are variables, like condition, timepoint or
+    /// subject
+    #[arg(long, requires = "group_samples_by")]
+    pub sample_metadata: Option<PathBuf>,
+    /// Column of the sample metadata used to pool samples
+    ///
+    /// The counts of the samples with the same value are summed
+    /// before calculating the values, so there is one column per
+    /// value in the output instead of one per sample.
+    #[arg(long, requires = "sample_metadata")]
+    pub group_samples_by: Option<String>,
+    /// Type of key in the first column of the map files
+    ///
+    /// Keys other than `uid` are resolved using the annotations
+    /// saved by `parse`. Using `contig`, the value is applied to
+    /// all annotations on that sequence.
+    #[arg(short = 'k', long, value_enum, default_value_t = MapKey::Uid)]
+    pub map_key: MapKey,
     /// Taxon rank to map taxa from the map (not implemented)
     #[arg(short = 'r', long, requires = "taxon_map")]
     pub taxon_rank: Option<String>,
@@ -114,12 +305,296 @@ pub struct Calc {
     /// Only save pN value, not pN/pS
     #[arg(short = 'n', long, group = "split")]
     pub output_pn: bool,
-    /// Taxonomy file, use `taxa-utils` `import` or `download` to create
+    /// GFF attribute used to label rows, instead of the UID
+    ///
+    /// Only used when no map is passed. If an annotation doesn't
+    /// have the attribute, the UID is used.
+    #[arg(long, value_enum)]
+    pub label: Option<LabelAttribute>,
+    /// Adds the sequence ID, start, end and strand columns
+    ///
+    /// Only used when no map is passed
+    #[arg(long)]
+    pub add_position: bool,
+    /// How to save the provenance of the input file
+    ///
+    /// `comments` writes it as lines starting with `#` before the CSV
+    /// header, `sidecar` to a JSON file named as the output file with
+    /// the `.provenance.json` suffix.
+    #[arg(long, value_enum, default_value_t = ProvenanceOutput::Sidecar)]
+    pub provenance: ProvenanceOutput,
+    /// Output of the `parse` command, binary or JSON
     pub input_file: PathBuf,
     /// Output file
     pub output_file: PathBuf,
 }
 
+/// Merges the output of several `parse` runs
+///
+/// The runs must use the same GFF and Fasta files, annotations are
+/// matched by position and the expected sites must be the same. The
+/// provenance of each input is kept.
+#[derive(Args, Debug)]
+pub struct Merge {
+    /// Renames duplicate sample IDs, instead of stopping
+    ///
+    /// The number of the input file (starting from 1) is appended
+    /// to the sample ID, e.g. `sample_2`
+    #[arg(short, long)]
+    pub rename_duplicates: bool,
+    /// Output file, the format is chosen as in `parse`
+    #[arg(short, long, required = true)]
+    pub output_file: PathBuf,
+    /// Output files of `parse` to merge
+    #[arg(required = true, num_args = 2..)]
+    pub input_files: Vec<PathBuf>,
+}
+
+/// Keeps only some samples and annotations from the output of `parse`
+///
+/// All lists are files with one element per line. When several
+/// lists are used to include annotations, only the annotations found
+/// in all of them are kept.
+#[derive(Args, Debug)]
+pub struct Subset {
+    /// Samples to keep
+    #[arg(short, long)]
+    pub samples: Option<PathBuf>,
+    /// Samples to remove
+    #[arg(short = 'S', long)]
+    pub exclude_samples: Option<PathBuf>,
+    /// Annotations to keep, see `--gene-key`
+    #[arg(short, long)]
+    pub genes: Option<PathBuf>,
+    /// Annotations to remove, see `--gene-key`
+    #[arg(short = 'G', long)]
+    pub exclude_genes: Option<PathBuf>,
+    /// Type of key used in the gene lists
+    #[arg(short = 'k', long, value_enum, default_value_t = MapKey::Uid)]
+    pub gene_key: MapKey,
+    /// Keeps only the annotations on these contigs
+    #[arg(short, long)]
+    pub contigs: Option<PathBuf>,
+    /// Removes the annotations on these contigs
+    #[arg(short = 'C', long)]
+    pub exclude_contigs: Option<PathBuf>,
+    /// Output file, the format is chosen as in `parse`
+    #[arg(short, long, required = true)]
+    pub output_file: PathBuf,
+    /// Output of the `parse` command
+    pub input_file: PathBuf,
+}
+
+/// Shows a summary of the output of `parse`
+///
+/// For each sample it shows the number of annotations passing the
+/// coverage threshold, the SNP counts, the coverage distribution, the
+/// fraction of annotations with pS = 0 and the top annotations by pN/pS.
+#[derive(Args, Debug)]
+pub struct Inspect {
+    /// Writes JSON instead of a table
+    #[arg(short, long)]
+    pub json: bool,
+    /// Number of top annotations by pN/pS to show per sample
+    #[arg(short, long, default_value_t = 10)]
+    pub top: usize,
+    /// Output to file, instead of stdout
+    #[arg(short, long)]
+    pub output_file: Option<PathBuf>,
+    /// Output of the `parse` command
+    pub input_file: PathBuf,
+}
+
+/// Tests differences in pN/pS between two groups of samples
+///
+/// For each annotation (or group, with a gene map) a Wilcoxon
+/// rank-sum test is used on the per-sample pN/pS values and a Fisher
+/// exact test on the nonsynonymous/synonymous counts pooled in each
+/// group. P-values are corrected with Benjamini-Hochberg.
+#[derive(Args, Debug)]
+pub struct Compare {
+    /// Sample metadata, `tab` separated with a header
+    ///
+    /// The first column is the sample ID, see `calc` for the header
+    #[arg(short = 'd', long, required = true)]
+    pub sample_metadata: PathBuf,
+    /// Column of the sample metadata defining the groups
+    #[arg(short = 'c', long, required = true)]
+    pub group_by: String,
+    /// The two values of the column to compare, the second is
+    /// compared to the first one
+    ///
+    /// Not needed if the column has only two values
+    #[arg(long, num_args = 2)]
+    pub groups: Option<Vec<String>>,
+    /// Gene map, mapping a UID to another ID
+    #[arg(short, long)]
+    pub gene_map: Option<PathBuf>,
+    /// Type of key in the first column of the gene map
+    #[arg(short = 'k', long, value_enum, default_value_t = MapKey::Uid)]
+    pub map_key: MapKey,
+    /// Pseudocount added to synonymous and nonsynonymous counts
+    #[arg(long, default_value_t = 0., value_parser = parse_non_negative)]
+    pub pseudocount: f64,
+    /// Output of the `parse` command
+    pub input_file: PathBuf,
+    /// Output file (CSV)
+    pub output_file: PathBuf,
+}
+
+/// GFF attributes that can be used to label annotations
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LabelAttribute {
+    #[value(name = "locus_tag")]
+    LocusTag,
+    #[value(name = "ID")]
+    Id,
+    #[value(name = "Name")]
+    Name,
+    #[value(name = "gene")]
+    Gene,
+}
+
+impl LabelAttribute {
+    /// Returns the attribute name as found in the GFF
+    pub fn as_attribute(&self) -> &'static str {
+        match self {
+            Self::LocusTag => "locus_tag",
+            Self::Id => "ID",
+            Self::Name => "Name",
+            Self::Gene => "gene",
+        }
+    }
+}
+
+/// Type of key used in map files
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MapKey {
+    #[value(name = "uid")]
+    Uid,
+    #[value(name = "locus_tag")]
+    LocusTag,
+    #[value(name = "ID")]
+    Id,
+    #[value(name = "contig")]
+    Contig,
+}
+
+impl MapKey {
+    /// Returns the GFF attribute name, `uid` and `contig` are not attributes
+    pub fn as_attribute(&self) -> &'static str {
+        match self {
+            Self::Uid => "uid",
+            Self::LocusTag => "locus_tag",
+            Self::Id => "ID",
+            Self::Contig => "contig",
+        }
+    }
+}
+
+impl std::fmt::Display for MapKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.as_attribute())
+    }
+}
+
+/// Aggregation methods for groups of genes
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Aggregate {
+    /// Ratio of the summed counts
+    Sum,
+    /// Mean of per-gene values
+    Mean,
+    /// Median of per-gene values
+    Median,
+    /// Mean of per-gene values, weighted by coverage
+    Weighted,
+}
+
+impl std::fmt::Display for Aggregate {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = match self {
+            Self::Sum => "sum",
+            Self::Mean => "mean",
+            Self::Median => "median",
+            Self::Weighted => "weighted",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Tests of the nonsynonymous/synonymous split against the expected sites
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NeutralityTest {
+    /// Exact binomial test of the nonsynonymous SNPs, with the fraction of
+    /// nonsynonymous sites as probability
+    Binomial,
+    /// Fisher exact test of SNPs against the expected sites
+    Fisher,
+}
+
+/// Transition/transversion ratio used by `parse`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kappa {
+    Value(f64),
+    /// Estimated for each sample
+    Estimate,
+}
+
+impl std::fmt::Display for Kappa {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Value(value) => write!(f, "{}", value),
+            Self::Estimate => write!(f, "estimate"),
+        }
+    }
+}
+
+/// Where the provenance is saved by `calc`
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProvenanceOutput {
+    /// As comment lines in the CSV file
+    Comments,
+    /// As a separate JSON file
+    Sidecar,
+    /// Not saved
+    None,
+}
+
+/// Parses a float and checks it's not negative
+fn parse_non_negative(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Err(err) => Err(err.to_string()),
+        Ok(value) if value < 0. || !value.is_finite() => {
+            Err(format!("{value} must be zero or a positive number"))
+        }
+        Ok(value) => Ok(value),
+    }
+}
+
+/// Parses a float and checks it's between 0 and 1 (excluded)
+fn parse_fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Err(err) => Err(err.to_string()),
+        Ok(value) if value <= 0. || value >= 1. => Err(format!("{value} must be between 0 and 1")),
+        Ok(value) => Ok(value),
+    }
+}
+
+/// Parses κ, a positive number or `estimate`
+fn parse_kappa(value: &str) -> Result<Kappa, String> {
+    if value == "estimate" {
+        return Ok(Kappa::Estimate);
+    }
+    match value.parse::<f64>() {
+        Err(_) => Err(format!("{value} must be a positive number or `estimate`")),
+        Ok(value) if value <= 0. || !value.is_finite() => {
+            Err(format!("{value} must be a positive number"))
+        }
+        Ok(value) => Ok(Kappa::Value(value)),
+    }
+}
+
 /// Generates the completion for the specified shell
 ///
 /// Slightly modified from example
@@ -0,0 +1,402 @@
+//! Codons of the coding sequences and their translation, used to describe the
+//! effect of the SNPs found by `parse`
+use anyhow::{bail, Result};
+use bio_rascal::gff::Annotation;
+
+/// Standard genetic code (also valid for table 11), with the bases in `TCAG`
+/// order
+static AMINO_ACIDS: &[u8; 64] = b"FFLLSSSSYY**CC*WLLLLPPPPHHQQRRRRIIIMTTTTNNKKSSRRVVVVAAAADDEEGGGG";
+
+fn base_index(base: u8) -> Option<usize> {
+    match base.to_ascii_uppercase() {
+        b'T' | b'U' => Some(0),
+        b'C' => Some(1),
+        b'A' => Some(2),
+        b'G' => Some(3),
+        _ => None,
+    }
+}
+
+/// Translates a codon, `X` is returned if the codon contains other bases
+pub fn translate(codon: &[u8]) -> u8 {
+    if codon.len() != 3 {
+        return b'X';
+    }
+    let mut index = 0;
+    for base in codon {
+        match base_index(*base) {
+            None => return b'X',
+            Some(value) => index = index * 4 + value,
+        }
+    }
+    AMINO_ACIDS[index]
+}
+
+pub fn complement(base: u8) -> u8 {
+    match base.to_ascii_uppercase() {
+        b'A' => b'T',
+        b'T' => b'A',
+        b'C' => b'G',
+        b'G' => b'C',
+        other => other,
+    }
+}
+
+/// True if the substitution is a transition (purine to purine or pyrimidine
+/// to pyrimidine), `None` if a base is not `ACGT`
+pub fn is_transition(ref_base: u8, alt_base: u8) -> Option<bool> {
+    let ref_index = base_index(ref_base)?;
+    let alt_index = base_index(alt_base)?;
+    if ref_index == alt_index {
+        return None;
+    }
+    // T and C are 0 and 1, A and G are 2 and 3
+    Some(ref_index / 2 == alt_index / 2)
+}
+
+/// Change in a codon caused by a SNP, bases are on the coding strand
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CodonChange {
+    /// Number of the codon in the CDS, starting from 1
+    pub codon_number: usize,
+    /// Position of the SNP in the codon, from 1 to 3
+    pub codon_position: usize,
+    pub ref_codon: [u8; 3],
+    pub alt_codon: [u8; 3],
+}
+
+impl CodonChange {
+    pub fn ref_aa(&self) -> u8 {
+        translate(&self.ref_codon)
+    }
+
+    pub fn alt_aa(&self) -> u8 {
+        translate(&self.alt_codon)
+    }
+
+    /// The SNP changes an amino acid codon into a stop codon
+    pub fn is_stop_gain(&self) -> bool {
+        self.ref_aa() != b'*' && self.alt_aa() == b'*'
+    }
+
+    /// The SNP changes a stop codon into an amino acid codon
+    pub fn is_stop_loss(&self) -> bool {
+        self.ref_aa() == b'*' && self.alt_aa() != b'*'
+    }
+
+    pub fn ref_codon_string(&self) -> String {
+        String::from_utf8_lossy(&self.ref_codon).to_string()
+    }
+
+    pub fn alt_codon_string(&self) -> String {
+        String::from_utf8_lossy(&self.alt_codon).to_string()
+    }
+}
+
+/// A CDS on its contig sequence, positions are 1-based as in GFF and VCF files
+pub struct CodingSequence<'a> {
+    seq: &'a [u8],
+    start: usize,
+    end: usize,
+    reverse: bool,
+}
+
+impl<'a> CodingSequence<'a> {
+    pub fn new<S: AsRef<[u8]> + ?Sized>(annotation: &Annotation, seq: &'a S) -> Self {
+        Self {
+            seq: seq.as_ref(),
+            start: annotation.start as usize,
+            end: annotation.end as usize,
+            reverse: annotation.strand.to_string() == "-",
+        }
+    }
+
+    /// Number of complete codons
+    pub fn n_codons(&self) -> usize {
+        (self.end + 1 - self.start) / 3
+    }
+
+    /// Returns the index of the codon (from 0) and the position of the base in
+    /// the codon (from 0), for a position on the contig
+    pub fn locate(&self, pos: usize) -> Option<(usize, usize)> {
+        if pos < self.start || pos > self.end {
+            return None;
+        }
+        let offset = match self.reverse {
+            false => pos - self.start,
+            true => self.end - pos,
+        };
+        Some((offset / 3, offset % 3))
+    }
+
+    /// Returns the codon, on the coding strand, by index (from 0)
+    pub fn codon(&self, index: usize) -> Option<[u8; 3]> {
+        if index >= self.n_codons() {
+            return None;
+        }
+        let mut codon = [b'N'; 3];
+        for (i, base) in codon.iter_mut().enumerate() {
+            let offset = index * 3 + i;
+            *base = match self.reverse {
+                false => self.seq.get(self.start - 1 + offset)?.to_ascii_uppercase(),
+                true => complement(*self.seq.get(self.end - 1 - offset)?),
+            };
+        }
+        Some(codon)
+    }
+
+    /// Expected synonymous and nonsynonymous sites (Nei and Gojobori, 1986),
+    /// with transitions weighted by `kappa` relative to transversions, so a
+    /// `kappa` of 1 counts all substitutions as equally likely. Changes to a
+    /// stop codon are nonsynonymous, codons with other bases are skipped.
+    pub fn expected_sites(&self, kappa: f64) -> (f64, f64) {
+        let mut syn = 0.;
+        let mut nonsyn = 0.;
+        for index in 0..self.n_codons() {
+            let codon = match self.codon(index) {
+                None => continue,
+                Some(value) => value,
+            };
+            let ref_aa = translate(&codon);
+            if ref_aa == b'X' {
+                continue;
+            }
+            for position in 0..3 {
+                let mut position_syn = 0.;
+                for base in [b'T', b'C', b'A', b'G'] {
+                    let weight = match is_transition(codon[position], base) {
+                        None => continue,
+                        Some(true) => kappa,
+                        Some(false) => 1.,
+                    };
+                    let mut alt_codon = codon;
+                    alt_codon[position] = base;
+                    if translate(&alt_codon) == ref_aa {
+                        position_syn += weight;
+                    }
+                }
+                // each base has one transition and two transversions
+                position_syn /= kappa + 2.;
+                syn += position_syn;
+                nonsyn += 1. - position_syn;
+            }
+        }
+        (syn, nonsyn)
+    }
+
+    /// Returns the index of the codon (from 0), the reference and the
+    /// alternative codon for SNPs in the same codon. The bases are given on
+    /// the forward strand, as in VCF files.
+    pub fn apply_snps(&self, snps: &[(usize, u8)]) -> Result<(usize, [u8; 3], [u8; 3])> {
+        let mut codon: Option<(usize, [u8; 3], [u8; 3])> = None;
+        for (pos, alt) in snps.iter() {
+            let (index, codon_position) = match self.locate(*pos) {
+                None => bail!("Position {} is outside of the CDS", pos),
+                Some(value) => value,
+            };
+            if codon.is_none() {
+                match self.codon(index) {
+                    None => bail!("Position {} is in an incomplete codon", pos),
+                    Some(ref_codon) => codon = Some((index, ref_codon, ref_codon)),
+                }
+            }
+            if let Some((codon_index, _, alt_codon)) = codon.as_mut() {
+                if *codon_index != index {
+                    bail!("SNPs are in different codons");
+                }
+                alt_codon[codon_position] = match self.reverse {
+                    false => alt.to_ascii_uppercase(),
+                    true => complement(*alt),
+                };
+            }
+        }
+        match codon {
+            None => bail!("No SNPs to apply"),
+            Some(value) => Ok(value),
+        }
+    }
+
+    /// Returns the codon change for the alternative base at the position, the
+    /// base is given on the forward strand, as in VCF files
+    pub fn codon_change(&self, pos: usize, alt: u8) -> Result<CodonChange> {
+        let (index, codon_position) = match self.locate(pos) {
+            None => bail!("Position {} is outside of the CDS", pos),
+            Some(value) => value,
+        };
+        let ref_codon = match self.codon(index) {
+            None => bail!("Position {} is in an incomplete codon", pos),
+            Some(value) => value,
+        };
+        let mut alt_codon = ref_codon;
+        alt_codon[codon_position] = match self.reverse {
+            false => alt.to_ascii_uppercase(),
+            true => complement(alt),
+        };
+        Ok(CodonChange {
+            codon_number: index + 1,
+            codon_position: codon_position + 1,
+            ref_codon,
+            alt_codon,
+        })
+    }
+}
+
+/// Returns all the orders of the elements
+fn permutations(items: &[usize]) -> Vec<Vec<usize>> {
+    if items.len() <= 1 {
+        return vec![items.to_vec()];
+    }
+    let mut result = vec![];
+    for (index, item) in items.iter().enumerate() {
+        let mut rest = items.to_vec();
+        rest.remove(index);
+        for mut permutation in permutations(&rest) {
+            permutation.insert(0, *item);
+            result.push(permutation);
+        }
+    }
+    result
+}
+
+/// Number of synonymous and nonsynonymous differences between two codons.
+/// With `pathway_averaging` the differences are averaged over the orders in
+/// which the mutations may have happened (Nei and Gojobori, 1986), excluding
+/// pathways going through a stop codon, so the numbers can be fractional.
+/// Otherwise, or if there are no valid pathways, all the differences are
+/// classified by the change of the whole codon.
+pub fn codon_differences(
+    ref_codon: &[u8; 3],
+    alt_codon: &[u8; 3],
+    pathway_averaging: bool,
+) -> (f64, f64) {
+    let positions: Vec<usize> = (0..3).filter(|i| ref_codon[*i] != alt_codon[*i]).collect();
+    let n_differences = positions.len() as f64;
+
+    if pathway_averaging && positions.len() > 1 {
+        let mut syn = 0.;
+        let mut nonsyn = 0.;
+        let mut n_pathways = 0;
+        'pathways: for pathway in permutations(&positions) {
+            let mut codon = *ref_codon;
+            let mut pathway_syn = 0.;
+            for (step, position) in pathway.iter().enumerate() {
+                let mut next = codon;
+                next[*position] = alt_codon[*position];
+                let next_aa = translate(&next);
+                if next_aa == b'*' && step + 1 < pathway.len() {
+                    continue 'pathways;
+                }
+                if translate(&codon) == next_aa {
+                    pathway_syn += 1.;
+                }
+                codon = next;
+            }
+            syn += pathway_syn;
+            nonsyn += n_differences - pathway_syn;
+            n_pathways += 1;
+        }
+        if n_pathways > 0 {
+            return (syn / n_pathways as f64, nonsyn / n_pathways as f64);
+        }
+    }
+
+    if translate(ref_codon) == translate(alt_codon) {
+        (n_differences, 0.)
+    } else {
+        (0., n_differences)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// CDS covering the whole sequence
+    fn coding_sequence(seq: &[u8], reverse: bool) -> CodingSequence<'_> {
+        CodingSequence {
+            seq,
+            start: 1,
+            end: seq.len(),
+            reverse,
+        }
+    }
+
+    #[test]
+    fn test_translate() {
+        assert_eq!(translate(b"ATG"), b'M');
+        assert_eq!(translate(b"atg"), b'M');
+        assert_eq!(translate(b"TAA"), b'*');
+        assert_eq!(translate(b"TGG"), b'W');
+        assert_eq!(translate(b"ANG"), b'X');
+        assert_eq!(translate(b"AT"), b'X');
+    }
+
+    #[test]
+    fn test_is_transition() {
+        assert_eq!(is_transition(b'A', b'G'), Some(true));
+        assert_eq!(is_transition(b'C', b'T'), Some(true));
+        assert_eq!(is_transition(b'C', b'A'), Some(false));
+        assert_eq!(is_transition(b'A', b'A'), None);
+        assert_eq!(is_transition(b'A', b'N'), None);
+    }
+
+    #[test]
+    fn test_expected_sites() {
+        // only the transition at the third position of TTT is synonymous
+        let (syn, nonsyn) = coding_sequence(b"TTT", false).expected_sites(1.);
+        assert!((syn - 1. / 3.).abs() < 1e-12);
+        assert!((nonsyn - 8. / 3.).abs() < 1e-12);
+        let (syn, nonsyn) = coding_sequence(b"ATGCTG", false).expected_sites(1.);
+        assert!((syn - 4. / 3.).abs() < 1e-12);
+        assert!((nonsyn - 14. / 3.).abs() < 1e-12);
+        // CTG, transitions twice as likely
+        let (syn, nonsyn) = coding_sequence(b"CTG", false).expected_sites(2.);
+        assert!((syn - 1.5).abs() < 1e-12);
+        assert!((nonsyn - 1.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn test_reverse_strand() {
+        let cds = coding_sequence(b"TTACAT", true);
+        assert_eq!(cds.n_codons(), 2);
+        assert_eq!(cds.locate(6), Some((0, 0)));
+        assert_eq!(cds.locate(1), Some((1, 2)));
+        assert_eq!(cds.locate(7), None);
+        assert_eq!(cds.codon(0), Some(*b"ATG"));
+        assert_eq!(cds.codon(1), Some(*b"TAA"));
+
+        let change = cds.codon_change(6, b'C').unwrap();
+        assert_eq!(change.codon_number, 1);
+        assert_eq!(change.codon_position, 1);
+        assert_eq!(&change.alt_codon, b"GTG");
+        let change = cds.codon_change(3, b'G').unwrap();
+        assert_eq!(change.alt_codon_string(), "CAA");
+        assert!(change.is_stop_loss());
+    }
+
+    #[test]
+    fn test_apply_snps() {
+        let cds = coding_sequence(b"ATGCTG", false);
+        let (index, ref_codon, alt_codon) = cds.apply_snps(&[(4, b'T'), (6, b'A')]).unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(&ref_codon, b"CTG");
+        assert_eq!(&alt_codon, b"TTA");
+        assert!(cds.apply_snps(&[(1, b'T'), (4, b'T')]).is_err());
+        assert!(cds.apply_snps(&[(7, b'T')]).is_err());
+        let change = coding_sequence(b"TGG", false)
+            .codon_change(3, b'A')
+            .unwrap();
+        assert!(change.is_stop_gain());
+    }
+
+    #[test]
+    fn test_codon_differences() {
+        // CTT (Leu) > TTA (Leu), through TTT (Phe) or CTA (Leu)
+        assert_eq!(codon_differences(b"CTT", b"TTA", true), (1., 1.));
+        assert_eq!(codon_differences(b"CTT", b"TTA", false), (2., 0.));
+        // both pathways go through a stop codon
+        assert_eq!(codon_differences(b"TGG", b"TAA", true), (0., 2.));
+        assert_eq!(codon_differences(b"AAA", b"GGG", true), (1., 2.));
+        assert_eq!(codon_differences(b"TTT", b"TTC", true), (1., 0.));
+    }
+}
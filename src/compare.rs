@@ -0,0 +1,175 @@
+use super::calc::{read_gene_map_file, GeneMap};
+use super::cli::Compare;
+use super::data::{read_parse_output, Counts, KeyResolver};
+use super::metadata::SampleMetadata;
+use super::stats::{benjamini_hochberg, fisher_exact, median, wilcoxon_rank_sum};
+use anyhow::{bail, Context, Result};
+use log::info;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+/// Values for one annotation, or group, in the two groups of samples
+#[derive(Debug, Default)]
+struct CompareData {
+    /// Per-sample pN/pS, only finite values
+    values: [Vec<f64>; 2],
+    /// Counts pooled over the samples
+    pooled: [Counts; 2],
+}
+
+pub fn compare_command(options: Compare) -> Result<()> {
+    let parse_output = read_parse_output(&options.input_file)?;
+    let metadata = SampleMetadata::read_from_file(&options.sample_metadata)?;
+    let sample_groups = metadata.get_groups(&options.group_by, parse_output.samples.keys())?;
+
+    let groups: Vec<String> = match options.groups {
+        Some(groups) => groups,
+        None => {
+            let values: BTreeSet<&String> = sample_groups.values().collect();
+            if values.len() != 2 {
+                bail!(
+                    "Column {} has {} values, use `--groups` to choose two",
+                    options.group_by,
+                    values.len()
+                );
+            }
+            values.into_iter().cloned().collect()
+        }
+    };
+    info!("Comparing {} to {}", groups[1], groups[0]);
+
+    let mut gene_map = GeneMap::new();
+    if let Some(gene_map_file) = options.gene_map {
+        let resolver = KeyResolver::new(options.map_key, &parse_output.annotations);
+        gene_map = read_gene_map_file(gene_map_file, &resolver)?;
+    }
+
+    let mut data: BTreeMap<String, CompareData> = BTreeMap::new();
+    let mut n_samples = [0usize; 2];
+    for (sample_id, sample_map) in parse_output.samples.iter() {
+        let index = match sample_groups.get(sample_id) {
+            Some(group) if group == &groups[0] => 0,
+            Some(group) if group == &groups[1] => 1,
+            _ => continue,
+        };
+        n_samples[index] += 1;
+        let sample_extra = parse_output.extra.get(sample_id);
+        // sums the counts of the annotations in the same group
+        let mut sample_counts: HashMap<String, Counts> = HashMap::new();
+        for (uid, pnps) in sample_map.iter() {
+            let gene_ids = match gene_map.get(uid) {
+                None if !gene_map.is_empty() => continue,
+                None => vec![uid.to_string()],
+                Some(values) => values.clone(),
+            };
+            for gene_id in gene_ids {
+                sample_counts.entry(gene_id).or_default().add(
+                    &Counts::from_pnps(pnps).with_extra(sample_extra.and_then(|m| m.get(uid))),
+                );
+            }
+        }
+        for (gene_id, counts) in sample_counts {
+            let entry = data.entry(gene_id).or_default();
+            entry.pooled[index].add(&counts);
+            let value = counts.with_pseudocount(options.pseudocount).get_pnps();
+            if value.is_finite() {
+                entry.values[index].push(value);
+            }
+        }
+    }
+    info!(
+        "Samples in {}: {}, in {}: {}",
+        groups[0], n_samples[0], groups[1], n_samples[1]
+    );
+    if n_samples[0] == 0 || n_samples[1] == 0 {
+        bail!("No samples found for one of the groups");
+    }
+
+    let wilcoxon: Vec<_> = data
+        .values()
+        .map(|d| wilcoxon_rank_sum(&d.values[0], &d.values[1]))
+        .collect();
+    let wilcoxon_q = benjamini_hochberg(
+        &wilcoxon
+            .iter()
+            .map(|r| r.map(|r| r.p_value).unwrap_or(f64::NAN))
+            .collect::<Vec<f64>>(),
+    );
+    let fisher_p: Vec<f64> = data
+        .values()
+        .map(|d| {
+            let [a, b] = &d.pooled;
+            fisher_exact(
+                a.nonsyn.round() as u64,
+                a.syn.round() as u64,
+                b.nonsyn.round() as u64,
+                b.syn.round() as u64,
+            )
+        })
+        .collect();
+    let fisher_q = benjamini_hochberg(&fisher_p);
+
+    info!("Writing results to file {}", options.output_file.display());
+    let mut writer =
+        csv::Writer::from_path(&options.output_file).context("Problem opening file")?;
+    writer
+        .write_record([
+            "gene_id",
+            "n_a",
+            "n_b",
+            "median_a",
+            "median_b",
+            "rank_biserial",
+            "wilcoxon_u",
+            "wilcoxon_p",
+            "wilcoxon_q",
+            "syn_a",
+            "nonsyn_a",
+            "syn_b",
+            "nonsyn_b",
+            "pnps_a",
+            "pnps_b",
+            "log2_ratio",
+            "fisher_p",
+            "fisher_q",
+        ])
+        .context("Problem writing Header")?;
+
+    for (index, (gene_id, gene_data)) in data.iter_mut().enumerate() {
+        let pnps_a = gene_data.pooled[0]
+            .with_pseudocount(options.pseudocount)
+            .get_pnps();
+        let pnps_b = gene_data.pooled[1]
+            .with_pseudocount(options.pseudocount)
+            .get_pnps();
+        let (rank_biserial, wilcoxon_u, wilcoxon_p) = match wilcoxon[index] {
+            None => (f64::NAN, f64::NAN, f64::NAN),
+            Some(result) => (result.rank_biserial, result.u, result.p_value),
+        };
+        let record = [
+            gene_id.clone(),
+            gene_data.values[0].len().to_string(),
+            gene_data.values[1].len().to_string(),
+            median(&mut gene_data.values[0]).to_string(),
+            median(&mut gene_data.values[1]).to_string(),
+            rank_biserial.to_string(),
+            wilcoxon_u.to_string(),
+            wilcoxon_p.to_string(),
+            wilcoxon_q[index].to_string(),
+            gene_data.pooled[0].syn.to_string(),
+            gene_data.pooled[0].nonsyn.to_string(),
+            gene_data.pooled[1].syn.to_string(),
+            gene_data.pooled[1].nonsyn.to_string(),
+            pnps_a.to_string(),
+            pnps_b.to_string(),
+            (pnps_b / pnps_a).log2().to_string(),
+            fisher_p[index].to_string(),
+            fisher_q[index].to_string(),
+        ];
+        writer
+            .write_record(&record)
+            .context("Problem writing Record")?;
+    }
+    writer.flush().context("Problem flushing to disk")?;
+
+    Ok(())
+}
@@ -0,0 +1,513 @@
+use super::binary::{is_binary_file, read_binary, write_binary};
+use super::cli::MapKey;
+use super::parse::SamplePnPs;
+use super::provenance::Provenance;
+use super::stats::{tajima_d, watterson_theta};
+use super::utils::{create_output_file, open_input_file};
+use anyhow::{bail, Context, Result};
+use bio_rascal::gff::Annotation;
+use bio_rascal::snps::PnPs;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use std::str::FromStr;
+use uuid::Uuid;
+
+/// GFF attributes kept in the annotation table, they can be used as labels
+/// in the output of `calc`
+pub static LABEL_ATTRIBUTES: [&str; 4] = ["locus_tag", "ID", "Name", "gene"];
+
+/// Minimal information about an annotation, saved in the output of `parse`
+/// so `calc` doesn't need the GFF file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AnnotationInfo {
+    pub seq_id: String,
+    pub start: usize,
+    pub end: usize,
+    pub strand: String,
+    /// Only the attributes in `LABEL_ATTRIBUTES` are kept
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+impl AnnotationInfo {
+    pub fn from_annotation(annotation: &Annotation) -> Self {
+        let attributes = LABEL_ATTRIBUTES
+            .iter()
+            .filter_map(|key| {
+                annotation
+                    .attributes
+                    .get(*key)
+                    .map(|value| (key.to_string(), value.clone()))
+            })
+            .collect();
+        Self {
+            seq_id: annotation.seq_id.clone(),
+            start: annotation.start as usize,
+            end: annotation.end as usize,
+            strand: annotation.strand.to_string(),
+            attributes,
+        }
+    }
+
+    /// Sequence, start, end and strand, used to match annotations between
+    /// files, as UIDs are different for each run of `parse`
+    pub fn location(&self) -> (String, usize, usize, String) {
+        (
+            self.seq_id.clone(),
+            self.start,
+            self.end,
+            self.strand.clone(),
+        )
+    }
+
+    /// Returns the value of the attribute, if present
+    pub fn get_attribute(&self, key: &str) -> Option<&String> {
+        self.attributes.get(key)
+    }
+}
+
+pub type AnnotationTable = HashMap<Uuid, AnnotationInfo>;
+
+/// Counts collected by `parse` that are not part of `PnPs`, for one gene in
+/// one sample
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ExtraCounts {
+    /// Nonsynonymous SNPs introducing a stop codon
+    #[serde(default)]
+    pub stop_gain: u32,
+    /// Nonsynonymous SNPs removing a stop codon
+    #[serde(default)]
+    pub stop_loss: u32,
+    /// Indels with a length multiple of 3
+    #[serde(default)]
+    pub inframe_indel: u32,
+    /// Indels shifting the reading frame
+    #[serde(default)]
+    pub frameshift_indel: u32,
+    /// Synonymous SNPs with pathway averaging, can be fractional. When
+    /// present, it is used instead of `syn` in `PnPs`, which is rounded.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fractional_syn: Option<f64>,
+    /// Nonsynonymous SNPs with pathway averaging, see `fractional_syn`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fractional_nonsyn: Option<f64>,
+    /// Nucleotide diversity summed over the sites of the gene, from the allele
+    /// frequencies in the sample
+    #[serde(default)]
+    pub pi: f64,
+    /// Part of `pi` from pairs of alleles with a synonymous difference
+    #[serde(default)]
+    pub pi_syn: f64,
+    /// Part of `pi` from pairs of alleles with a nonsynonymous difference
+    #[serde(default)]
+    pub pi_nonsyn: f64,
+    /// Sites of the gene with more than one allele in the sample
+    #[serde(default)]
+    pub segregating_sites: u32,
+    /// Folded site frequency spectrum of the synonymous sites, the number of
+    /// sites in each bin of minor allele frequency, empty if not computed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sfs_syn: Vec<u32>,
+    /// Folded site frequency spectrum of the nonsynonymous sites
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sfs_nonsyn: Vec<u32>,
+    /// Transition/transversion ratio used to weight the expected sites,
+    /// `None` if they are not weighted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub kappa: Option<f64>,
+}
+
+impl ExtraCounts {
+    pub fn add(&mut self, other: &ExtraCounts) {
+        self.stop_gain += other.stop_gain;
+        self.stop_loss += other.stop_loss;
+        self.inframe_indel += other.inframe_indel;
+        self.frameshift_indel += other.frameshift_indel;
+        self.pi += other.pi;
+        self.pi_syn += other.pi_syn;
+        self.pi_nonsyn += other.pi_nonsyn;
+        self.segregating_sites += other.segregating_sites;
+        add_histogram(&mut self.sfs_syn, &other.sfs_syn);
+        add_histogram(&mut self.sfs_nonsyn, &other.sfs_nonsyn);
+        // the sum is only known if both are fractional
+        self.fractional_syn = match (self.fractional_syn, other.fractional_syn) {
+            (Some(a), Some(b)) => Some(a + b),
+            _ => None,
+        };
+        self.fractional_nonsyn = match (self.fractional_nonsyn, other.fractional_nonsyn) {
+            (Some(a), Some(b)) => Some(a + b),
+            _ => None,
+        };
+        if self.kappa != other.kappa {
+            self.kappa = None;
+        }
+    }
+}
+
+/// Adds the counts of each bin, `values` is extended if shorter
+fn add_histogram(values: &mut Vec<u32>, other: &[u32]) {
+    if values.len() < other.len() {
+        values.resize(other.len(), 0);
+    }
+    for (value, other) in values.iter_mut().zip(other.iter()) {
+        *value += other;
+    }
+}
+
+/// Extra counts for each sample and annotation, with the same keys as
+/// `SamplePnPs`
+pub type SampleExtra = HashMap<String, HashMap<Uuid, ExtraCounts>>;
+
+/// Data saved by `parse` and used by `calc`
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ParseOutput {
+    /// One element for each command that produced the data
+    #[serde(default)]
+    pub provenance: Vec<Provenance>,
+    pub annotations: AnnotationTable,
+    pub samples: SamplePnPs,
+    /// Empty for files written before the extra counts were added
+    #[serde(default)]
+    pub extra: SampleExtra,
+}
+
+/// Counts used to calculate pN/pS, for one gene or summed over a group
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Counts {
+    pub syn: f64,
+    pub nonsyn: f64,
+    pub exp_syn: f64,
+    pub exp_nonsyn: f64,
+    pub stop_gain: f64,
+    pub stop_loss: f64,
+    pub inframe_indel: f64,
+    pub frameshift_indel: f64,
+    pub pi: f64,
+    pub pi_syn: f64,
+    pub pi_nonsyn: f64,
+    pub segregating_sites: f64,
+    /// Mean depth, the sample size for Watterson's θ and Tajima's D. It is
+    /// not summed by `add`.
+    pub coverage: f64,
+}
+
+impl Counts {
+    pub fn from_pnps(pnps: &PnPs) -> Self {
+        Self {
+            syn: pnps.syn as f64,
+            nonsyn: pnps.nonsyn as f64,
+            exp_syn: pnps.exp_syn,
+            exp_nonsyn: pnps.exp_nonsyn,
+            coverage: pnps.coverage as f64,
+            ..Default::default()
+        }
+    }
+
+    /// Sets the extra counts, which are `NaN` if not available. The fractional
+    /// SNP counts replace the synonymous and nonsynonymous counts.
+    pub fn with_extra(mut self, extra: Option<&ExtraCounts>) -> Self {
+        match extra {
+            None => {
+                self.stop_gain = f64::NAN;
+                self.stop_loss = f64::NAN;
+                self.inframe_indel = f64::NAN;
+                self.frameshift_indel = f64::NAN;
+                self.pi = f64::NAN;
+                self.pi_syn = f64::NAN;
+                self.pi_nonsyn = f64::NAN;
+                self.segregating_sites = f64::NAN;
+            }
+            Some(extra) => {
+                self.stop_gain = extra.stop_gain as f64;
+                self.stop_loss = extra.stop_loss as f64;
+                self.inframe_indel = extra.inframe_indel as f64;
+                self.frameshift_indel = extra.frameshift_indel as f64;
+                self.pi = extra.pi;
+                self.pi_syn = extra.pi_syn;
+                self.pi_nonsyn = extra.pi_nonsyn;
+                self.segregating_sites = extra.segregating_sites as f64;
+                if let Some(syn) = extra.fractional_syn {
+                    self.syn = syn;
+                }
+                if let Some(nonsyn) = extra.fractional_nonsyn {
+                    self.nonsyn = nonsyn;
+                }
+            }
+        }
+        self
+    }
+
+    /// The pseudocount is added to the synonymous and nonsynonymous counts
+    pub fn with_pseudocount(mut self, pseudocount: f64) -> Self {
+        self.syn += pseudocount;
+        self.nonsyn += pseudocount;
+        self
+    }
+
+    pub fn add(&mut self, other: &Counts) {
+        self.syn += other.syn;
+        self.nonsyn += other.nonsyn;
+        self.exp_syn += other.exp_syn;
+        self.exp_nonsyn += other.exp_nonsyn;
+        self.stop_gain += other.stop_gain;
+        self.stop_loss += other.stop_loss;
+        self.inframe_indel += other.inframe_indel;
+        self.frameshift_indel += other.frameshift_indel;
+        self.pi += other.pi;
+        self.pi_syn += other.pi_syn;
+        self.pi_nonsyn += other.pi_nonsyn;
+        self.segregating_sites += other.segregating_sites;
+    }
+
+    /// Nucleotide diversity per site, the sites are the expected ones
+    pub fn get_pi(&self) -> f64 {
+        self.pi / (self.exp_syn + self.exp_nonsyn)
+    }
+
+    pub fn get_pin(&self) -> f64 {
+        self.pi_nonsyn / self.exp_nonsyn
+    }
+
+    pub fn get_pis(&self) -> f64 {
+        self.pi_syn / self.exp_syn
+    }
+
+    pub fn get_pinpis(&self) -> f64 {
+        self.get_pin() / self.get_pis()
+    }
+
+    /// Watterson's θ per site, the sites are the expected ones
+    pub fn get_watterson_theta(&self) -> f64 {
+        watterson_theta(self.segregating_sites, self.coverage.round() as u64)
+            / (self.exp_syn + self.exp_nonsyn)
+    }
+
+    pub fn get_tajima_d(&self) -> f64 {
+        tajima_d(
+            self.pi,
+            self.segregating_sites,
+            self.coverage.round() as u64,
+        )
+    }
+
+    pub fn get_pn(&self) -> f64 {
+        self.nonsyn / self.exp_nonsyn
+    }
+
+    pub fn get_ps(&self) -> f64 {
+        self.syn / self.exp_syn
+    }
+
+    pub fn get_pnps(&self) -> f64 {
+        self.get_pn() / self.get_ps()
+    }
+}
+
+/// Writes a JSON object with one value for each sample, one sample at a time
+fn write_sample_values<W: Write, T: Serialize>(
+    writer: &mut W,
+    values: HashMap<String, T>,
+) -> Result<()> {
+    writer.write_all(b"{")?;
+    for (index, (sample_id, sample_values)) in values.into_iter().enumerate() {
+        if index > 0 {
+            writer.write_all(b",")?;
+        }
+        serde_json::to_writer(&mut *writer, &sample_id)?;
+        writer.write_all(b":")?;
+        serde_json::to_writer(&mut *writer, &sample_values)
+            .with_context(|| format!("Cannot write data for sample {}", sample_id))?;
+    }
+    writer.write_all(b"}")?;
+    Ok(())
+}
+
+/// Writes the output of `parse` as JSON, one sample at a time, so the whole
+/// document is not built in memory. The layout is the same as serializing a
+/// `ParseOutput`.
+pub fn write_parse_output<W: Write>(mut writer: W, parse_output: ParseOutput) -> Result<()> {
+    writer.write_all(b"{\"provenance\":")?;
+    serde_json::to_writer(&mut writer, &parse_output.provenance)?;
+    writer.write_all(b",\"annotations\":")?;
+    serde_json::to_writer(&mut writer, &parse_output.annotations)?;
+    writer.write_all(b",\"samples\":")?;
+    write_sample_values(&mut writer, parse_output.samples)?;
+    writer.write_all(b",\"extra\":")?;
+    write_sample_values(&mut writer, parse_output.extra)?;
+    writer.write_all(b"}")?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns true if the file name has a `.json` extension, before the one for
+/// compression if present
+pub fn is_json_file_name<P: AsRef<Path>>(file_name: P) -> bool {
+    let file_name = file_name.as_ref().to_string_lossy();
+    [".json", ".json.gz", ".json.zst"]
+        .iter()
+        .any(|ext| file_name.ends_with(ext))
+}
+
+/// Reads the JSON up to the first key of the top object, returns the bytes
+/// read and the key, `None` if the object is empty
+fn read_first_key<R: Read>(reader: &mut R) -> Result<(Vec<u8>, Option<String>)> {
+    let mut prefix: Vec<u8> = vec![];
+    let mut next_byte = |prefix: &mut Vec<u8>| -> Result<u8> {
+        let mut byte = [0u8];
+        reader
+            .read_exact(&mut byte)
+            .context("Unexpected end of the input file")?;
+        prefix.push(byte[0]);
+        Ok(byte[0])
+    };
+    let mut next_token = |prefix: &mut Vec<u8>| -> Result<u8> {
+        loop {
+            let byte = next_byte(prefix)?;
+            if !byte.is_ascii_whitespace() {
+                return Ok(byte);
+            }
+        }
+    };
+    if next_token(&mut prefix)? != b'{' {
+        bail!("The input file is not a JSON object");
+    }
+    match next_token(&mut prefix)? {
+        b'}' => return Ok((prefix, None)),
+        b'"' => {}
+        _ => bail!("The input file is not valid JSON"),
+    }
+    let key_start = prefix.len() - 1;
+    loop {
+        match next_byte(&mut prefix)? {
+            b'\\' => {
+                next_byte(&mut prefix)?;
+            }
+            b'"' => break,
+            _ => {}
+        }
+    }
+    let key: String =
+        serde_json::from_slice(&prefix[key_start..]).context("The input file is not valid JSON")?;
+    Ok((prefix, Some(key)))
+}
+
+/// Reads the output of `parse`, either in binary or JSON format. JSON files
+/// written before the annotation table was added only have the samples, they
+/// are recognised by the first key (a sample ID) and have no annotations, so
+/// the UIDs are used as labels.
+pub fn read_parse_output<P: AsRef<Path>>(file_name: P) -> Result<ParseOutput> {
+    if is_binary_file(&file_name)? {
+        return read_binary(file_name);
+    }
+    let mut file_handle = open_input_file(&file_name).context("Cannot open the input file")?;
+    let (prefix, first_key) = read_first_key(&mut file_handle)?;
+    let reader = Cursor::new(prefix).chain(file_handle);
+    let is_current = matches!(
+        first_key.as_deref(),
+        Some("provenance" | "annotations" | "samples" | "extra")
+    );
+    if is_current {
+        return serde_json::from_reader(reader).context(
+            "Problem parsing the input file, it may be from an incompatible version of `parse`",
+        );
+    }
+    warn!(
+        "{} has no annotation table (older version of `parse`), UIDs are used as labels",
+        file_name.as_ref().display()
+    );
+    let samples: SamplePnPs =
+        serde_json::from_reader(reader).context("Problem parsing the input file")?;
+    Ok(ParseOutput {
+        provenance: vec![],
+        annotations: AnnotationTable::new(),
+        samples,
+        extra: SampleExtra::new(),
+    })
+}
+
+/// Checks the output file name, the binary format is not compressed so the
+/// compression extensions are only accepted for JSON
+pub fn check_output_file_name<P: AsRef<Path>>(file_name: P) -> Result<()> {
+    let extension = file_name.as_ref().extension().and_then(|e| e.to_str());
+    if !is_json_file_name(&file_name) && matches!(extension, Some("gz") | Some("zst")) {
+        bail!(
+            "The binary format is not compressed, use a name ending in `.json.gz` or `.json.zst` for compressed JSON: {}",
+            file_name.as_ref().display()
+        );
+    }
+    Ok(())
+}
+
+/// Saves the output of `parse`, JSON is used if the file name has a `.json`
+/// extension (see `is_json_file_name`), otherwise the binary format is used
+pub fn save_parse_output<P: AsRef<Path>>(file_name: P, parse_output: ParseOutput) -> Result<()> {
+    check_output_file_name(&file_name)?;
+    if is_json_file_name(&file_name) {
+        let mut file_handle = create_output_file(&file_name)?;
+        write_parse_output(&mut file_handle, parse_output)?;
+        file_handle.finish()
+    } else {
+        write_binary(file_name, &parse_output)
+    }
+}
+
+/// Resolves the keys used in map files to the annotation UIDs, using the
+/// annotation table
+pub struct KeyResolver {
+    map_key: MapKey,
+    index: HashMap<String, Vec<Uuid>>,
+}
+
+impl KeyResolver {
+    pub fn new(map_key: MapKey, annotations: &AnnotationTable) -> Self {
+        let mut index: HashMap<String, Vec<Uuid>> = HashMap::new();
+        for (uid, annotation) in annotations.iter() {
+            let key = match map_key {
+                MapKey::Uid => continue,
+                MapKey::Contig => Some(&annotation.seq_id),
+                _ => annotation.get_attribute(map_key.as_attribute()),
+            };
+            if let Some(key) = key {
+                index.entry(key.clone()).or_default().push(*uid);
+            }
+        }
+        Self { map_key, index }
+    }
+
+    /// Returns the UIDs corresponding to the key, an empty vector is returned
+    /// if the key cannot be found
+    pub fn resolve(&self, key: &str) -> Result<Vec<Uuid>> {
+        match self.map_key {
+            MapKey::Uid => Ok(vec![
+                Uuid::from_str(key).context("Cannot parse UID in line")?
+            ]),
+            _ => Ok(self.index.get(key).cloned().unwrap_or_default()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_first_key() {
+        let mut reader = Cursor::new(b" {\n  \"provenance\": []}".to_vec());
+        let (prefix, key) = read_first_key(&mut reader).unwrap();
+        assert_eq!(key.as_deref(), Some("provenance"));
+        assert_eq!(prefix, b" {\n  \"provenance\"");
+        // legacy file, starting with a sample ID
+        let mut reader = Cursor::new(br#"{"S\"1": {}}"#.to_vec());
+        assert_eq!(
+            read_first_key(&mut reader).unwrap().1.as_deref(),
+            Some("S\"1")
+        );
+        let mut reader = Cursor::new(b"{ }".to_vec());
+        assert_eq!(read_first_key(&mut reader).unwrap().1, None);
+        assert!(read_first_key(&mut Cursor::new(b"[]".to_vec())).is_err());
+        assert!(read_first_key(&mut Cursor::new(b"{\"sample".to_vec())).is_err());
+    }
+}
@@ -0,0 +1,255 @@
+use super::cli::Inspect;
+use super::data::{read_parse_output, AnnotationTable, Counts, ExtraCounts, ParseOutput};
+use super::stats::quantile;
+use super::utils::file_or_stdout;
+use anyhow::{Context, Result};
+use bio_rascal::snps::PnPs;
+use console::style;
+use serde::Serialize;
+use std::io::Write;
+
+/// Summary of the coverage of the annotations in a sample
+#[derive(Debug, Default, Serialize)]
+struct CoverageSummary {
+    min: f64,
+    q1: f64,
+    median: f64,
+    q3: f64,
+    max: f64,
+    mean: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct TopGene {
+    uid: String,
+    label: String,
+    pnps: f64,
+    syn: f64,
+    nonsyn: f64,
+}
+
+#[derive(Debug, Serialize)]
+struct SampleStats {
+    sample_id: String,
+    /// Annotations passing the coverage threshold
+    n_genes: usize,
+    syn: f64,
+    nonsyn: f64,
+    coverage: CoverageSummary,
+    /// Fraction of annotations without synonymous SNPs
+    ps_zero_fraction: f64,
+    /// Stop codon and indel counts, summed over the annotations
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extra: Option<ExtraCounts>,
+    top_genes: Vec<TopGene>,
+}
+
+fn summarise_coverage(mut values: Vec<f64>) -> CoverageSummary {
+    if values.is_empty() {
+        return CoverageSummary::default();
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    CoverageSummary {
+        min: values[0],
+        q1: quantile(&values, 0.25),
+        median: quantile(&values, 0.5),
+        q3: quantile(&values, 0.75),
+        max: values[values.len() - 1],
+        mean: values.iter().sum::<f64>() / values.len() as f64,
+    }
+}
+
+/// Uses the first attribute available, or the UID
+fn get_label(pnps: &PnPs, annotations: &AnnotationTable) -> String {
+    annotations
+        .get(&pnps.uid)
+        .and_then(|a| {
+            a.get_attribute("locus_tag")
+                .or_else(|| a.get_attribute("ID"))
+                .cloned()
+        })
+        .unwrap_or_else(|| pnps.uid.to_string())
+}
+
+fn get_sample_stats(parse_output: &ParseOutput, top: usize) -> Vec<SampleStats> {
+    let mut sample_ids: Vec<&String> = parse_output.samples.keys().collect();
+    sample_ids.sort();
+
+    let mut stats = Vec::with_capacity(sample_ids.len());
+    for sample_id in sample_ids {
+        let sample_map = &parse_output.samples[sample_id];
+        let sample_extra = parse_output.extra.get(sample_id);
+        // same counts as `calc`, with the fractional counts if available
+        let counts: Vec<(&PnPs, Counts)> = sample_map
+            .iter()
+            .map(|(uid, p)| {
+                let extra = sample_extra.and_then(|m| m.get(uid));
+                (p, Counts::from_pnps(p).with_extra(extra))
+            })
+            .collect();
+        let n_genes = sample_map.len();
+        let syn: f64 = counts.iter().map(|(_, c)| c.syn).sum();
+        let nonsyn: f64 = counts.iter().map(|(_, c)| c.nonsyn).sum();
+        let ps_zero = counts.iter().filter(|(_, c)| c.syn == 0.).count();
+        let coverage = summarise_coverage(sample_map.values().map(|p| p.coverage as f64).collect());
+
+        let mut top_genes: Vec<TopGene> = counts
+            .iter()
+            .map(|(p, c)| TopGene {
+                uid: p.uid.to_string(),
+                label: get_label(p, &parse_output.annotations),
+                pnps: c.get_pnps(),
+                syn: c.syn,
+                nonsyn: c.nonsyn,
+            })
+            .filter(|g| g.pnps.is_finite())
+            .collect();
+        top_genes.sort_by(|a, b| b.pnps.total_cmp(&a.pnps));
+        top_genes.truncate(top);
+
+        let extra = parse_output.extra.get(sample_id).map(|m| {
+            let mut total = ExtraCounts::default();
+            for counts in m.values() {
+                total.add(counts);
+            }
+            total
+        });
+
+        stats.push(SampleStats {
+            sample_id: sample_id.clone(),
+            n_genes,
+            syn,
+            nonsyn,
+            coverage,
+            ps_zero_fraction: match n_genes {
+                0 => f64::NAN,
+                n_genes => ps_zero as f64 / n_genes as f64,
+            },
+            extra,
+            top_genes,
+        });
+    }
+    stats
+}
+
+fn print_stats<W: Write>(
+    writer: &mut W,
+    parse_output: &ParseOutput,
+    stats: &[SampleStats],
+) -> Result<()> {
+    writeln!(
+        writer,
+        "Annotations: {}, Samples: {}",
+        style(parse_output.annotations.len()).blue(),
+        style(stats.len()).blue()
+    )?;
+    for provenance in parse_output.provenance.iter() {
+        writeln!(
+            writer,
+            "Produced by {} {} ({})",
+            style(&provenance.command).blue(),
+            provenance.version,
+            provenance.timestamp
+        )?;
+    }
+    writeln!(writer)?;
+    writeln!(
+        writer,
+        "{}",
+        style(format!(
+            "{:<20} {:>8} {:>10} {:>10} {:>8} {:>8} {:>8} {:>8} {:>8} {:>7}",
+            "Sample",
+            "Genes",
+            "Syn",
+            "Nonsyn",
+            "Cov min",
+            "Cov Q1",
+            "Cov med",
+            "Cov Q3",
+            "Cov max",
+            "pS=0"
+        ))
+        .bold()
+    )?;
+    for sample in stats.iter() {
+        writeln!(
+            writer,
+            "{:<20} {:>8} {:>10} {:>10} {:>8.1} {:>8.1} {:>8.1} {:>8.1} {:>8.1} {:>6.1}%",
+            style(&sample.sample_id).blue(),
+            sample.n_genes,
+            sample.syn,
+            sample.nonsyn,
+            sample.coverage.min,
+            sample.coverage.q1,
+            sample.coverage.median,
+            sample.coverage.q3,
+            sample.coverage.max,
+            sample.ps_zero_fraction * 100.
+        )?;
+    }
+
+    if stats.iter().any(|s| s.extra.is_some()) {
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "{}",
+            style(format!(
+                "{:<20} {:>10} {:>10} {:>10} {:>10}",
+                "Sample", "Stop gain", "Stop loss", "In-frame", "Frameshift"
+            ))
+            .bold()
+        )?;
+        for sample in stats.iter() {
+            if let Some(extra) = &sample.extra {
+                writeln!(
+                    writer,
+                    "{:<20} {:>10} {:>10} {:>10} {:>10}",
+                    style(&sample.sample_id).blue(),
+                    extra.stop_gain,
+                    extra.stop_loss,
+                    extra.inframe_indel,
+                    extra.frameshift_indel
+                )?;
+            }
+        }
+    }
+
+    for sample in stats.iter() {
+        if sample.top_genes.is_empty() {
+            continue;
+        }
+        writeln!(writer)?;
+        writeln!(
+            writer,
+            "Top genes by pN/pS in {}",
+            style(&sample.sample_id).blue()
+        )?;
+        for gene in sample.top_genes.iter() {
+            writeln!(
+                writer,
+                " | {:<40} {:>8.3} (syn {}, nonsyn {})",
+                gene.label,
+                style(gene.pnps).yellow(),
+                gene.syn,
+                gene.nonsyn
+            )?;
+        }
+    }
+    Ok(())
+}
+
+pub fn inspect_command(options: Inspect) -> Result<()> {
+    let parse_output = read_parse_output(&options.input_file)?;
+    let stats = get_sample_stats(&parse_output, options.top);
+
+    let mut output_file = file_or_stdout(&options.output_file)?;
+    if options.json {
+        serde_json::to_writer_pretty(&mut output_file, &stats).context("Problem writing JSON")?;
+        writeln!(output_file)?;
+    } else {
+        print_stats(&mut output_file, &parse_output, &stats)?;
+    }
+    output_file.flush()?;
+
+    Ok(())
+}
@@ -1,17 +1,34 @@
+mod binary;
 mod calc;
 mod cli;
+mod codon;
+mod compare;
 mod config;
+mod data;
+mod inspect;
+mod merge;
+mod metadata;
 mod parse;
+mod provenance;
+mod snp_table;
+mod spectrum;
+mod stats;
+mod subset;
 mod utils;
+mod vcf;
 
 use anyhow::Result;
 use calc::calc_command;
 use clap::{CommandFactory, Parser};
 use cli::print_completions;
+use compare::compare_command;
 use config::config_command;
 use env_logger::Env;
+use inspect::inspect_command;
 use log::{error, info};
+use merge::merge_command;
 use parse::parse_command;
+use subset::subset_command;
 
 fn main() -> Result<()> {
     let cli = cli::Cli::parse();
@@ -30,6 +47,10 @@ fn main() -> Result<()> {
             cli::Commands::Config(options) => config_command(options),
             cli::Commands::Parse(options) => parse_command(options),
             cli::Commands::Calc(options) => calc_command(options),
+            cli::Commands::Merge(options) => merge_command(options),
+            cli::Commands::Subset(options) => subset_command(options),
+            cli::Commands::Inspect(options) => inspect_command(options),
+            cli::Commands::Compare(options) => compare_command(options),
             //_ => todo!(),
         };
 
@@ -0,0 +1,166 @@
+use super::cli::Merge;
+use super::data::{read_parse_output, save_parse_output, ExtraCounts, ParseOutput};
+use super::provenance::Provenance;
+use anyhow::{bail, Result};
+use console::style;
+use log::{info, warn};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+/// Maximum difference accepted between expected sites
+const EXP_TOLERANCE: f64 = 1e-6;
+
+/// Annotation and κ used for its expected sites, which differ between samples
+/// when κ is estimated for each sample. The bits of κ are used as key.
+type SitesKey = (Uuid, Option<u64>);
+
+fn sites_key(uid: Uuid, extra: Option<&HashMap<Uuid, ExtraCounts>>) -> SitesKey {
+    let kappa = extra.and_then(|m| m.get(&uid)).and_then(|e| e.kappa);
+    (uid, kappa.map(f64::to_bits))
+}
+
+/// Returns a map from the UIDs in `other` to the UIDs in `merged`, matching the
+/// annotations by location
+fn match_annotations(merged: &ParseOutput, other: &ParseOutput) -> Result<HashMap<Uuid, Uuid>> {
+    // files written before the annotations were saved
+    if merged.annotations.is_empty() || other.annotations.is_empty() {
+        bail!("Cannot merge files without the annotation table, run `parse` again");
+    }
+    if merged.annotations.len() != other.annotations.len() {
+        bail!(
+            "Different number of annotations: {} and {}",
+            merged.annotations.len(),
+            other.annotations.len()
+        );
+    }
+    let locations: HashMap<_, Uuid> = merged
+        .annotations
+        .iter()
+        .map(|(uid, annotation)| (annotation.location(), *uid))
+        .collect();
+
+    let mut uid_map: HashMap<Uuid, Uuid> = HashMap::with_capacity(other.annotations.len());
+    for (uid, annotation) in other.annotations.iter() {
+        match locations.get(&annotation.location()) {
+            None => bail!(
+                "Annotation on {} ({}-{}) not found in the other files",
+                annotation.seq_id,
+                annotation.start,
+                annotation.end
+            ),
+            Some(merged_uid) => uid_map.insert(*uid, *merged_uid),
+        };
+    }
+    Ok(uid_map)
+}
+
+/// Expected synonymous and nonsynonymous sites for each annotation and κ,
+/// taken from the first sample where they are found
+fn get_expected_sites(parse_output: &ParseOutput) -> HashMap<SitesKey, (f64, f64)> {
+    let mut exp_sites: HashMap<SitesKey, (f64, f64)> = HashMap::new();
+    for (sample_id, sample_map) in parse_output.samples.iter() {
+        let sample_extra = parse_output.extra.get(sample_id);
+        for (uid, pnps) in sample_map.iter() {
+            exp_sites
+                .entry(sites_key(*uid, sample_extra))
+                .or_insert((pnps.exp_syn, pnps.exp_nonsyn));
+        }
+    }
+    exp_sites
+}
+
+pub fn merge_command(options: Merge) -> Result<()> {
+    let mut provenance = Provenance::new("merge");
+    provenance.add_parameter("rename_duplicates", options.rename_duplicates);
+    for input_file in options.input_files.iter() {
+        provenance.add_input_file("input_file", input_file)?;
+    }
+
+    let mut merged = read_parse_output(&options.input_files[0])?;
+    let mut exp_sites = get_expected_sites(&merged);
+    info!(
+        "Samples in {}: {}",
+        options.input_files[0].display(),
+        merged.samples.len()
+    );
+
+    for (index, input_file) in options.input_files.iter().enumerate().skip(1) {
+        let other = read_parse_output(input_file)?;
+        info!(
+            "Samples in {}: {}",
+            input_file.display(),
+            other.samples.len()
+        );
+        let uid_map = match_annotations(&merged, &other)?;
+
+        let mut other_extra = other.extra;
+        for (sample_id, sample_map) in other.samples {
+            let sample_extra = other_extra.remove(&sample_id);
+            let new_sample_id = if !merged.samples.contains_key(&sample_id) {
+                sample_id
+            } else if options.rename_duplicates {
+                let new_sample_id = format!("{}_{}", sample_id, index + 1);
+                if merged.samples.contains_key(&new_sample_id) {
+                    bail!(
+                        "Cannot rename sample {}, {} exists",
+                        sample_id,
+                        new_sample_id
+                    );
+                }
+                warn!(
+                    "Renaming sample {} to {}",
+                    style(&sample_id).yellow(),
+                    style(&new_sample_id).yellow()
+                );
+                new_sample_id
+            } else {
+                bail!(
+                    "Sample {} in {} is already present, use `--rename-duplicates`",
+                    sample_id,
+                    input_file.display()
+                );
+            };
+
+            let mut new_sample_map = HashMap::with_capacity(sample_map.len());
+            for (uid, mut pnps) in sample_map {
+                let new_uid = match uid_map.get(&uid) {
+                    None => bail!("Annotation {} not found in the other files", uid),
+                    Some(value) => *value,
+                };
+                let (_, kappa) = sites_key(uid, sample_extra.as_ref());
+                let (exp_syn, exp_nonsyn) = *exp_sites
+                    .entry((new_uid, kappa))
+                    .or_insert((pnps.exp_syn, pnps.exp_nonsyn));
+                if (exp_syn - pnps.exp_syn).abs() > EXP_TOLERANCE
+                    || (exp_nonsyn - pnps.exp_nonsyn).abs() > EXP_TOLERANCE
+                {
+                    bail!(
+                        "Expected sites for annotation {} in sample {} differ from the other files",
+                        new_uid,
+                        new_sample_id
+                    );
+                }
+                pnps.uid = new_uid;
+                new_sample_map.insert(new_uid, pnps);
+            }
+            if let Some(sample_extra) = sample_extra {
+                let mut new_sample_extra = HashMap::with_capacity(sample_extra.len());
+                for (uid, counts) in sample_extra {
+                    match uid_map.get(&uid) {
+                        None => bail!("Annotation {} not found in the other files", uid),
+                        Some(new_uid) => new_sample_extra.insert(*new_uid, counts),
+                    };
+                }
+                merged.extra.insert(new_sample_id.clone(), new_sample_extra);
+            }
+            merged.samples.insert(new_sample_id, new_sample_map);
+        }
+        merged.provenance.extend(other.provenance);
+    }
+
+    merged.provenance.push(provenance);
+    info!("Number of merged samples: {}", merged.samples.len());
+    save_parse_output(&options.output_file, merged)?;
+
+    Ok(())
+}
@@ -0,0 +1,135 @@
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::io::BufRead;
+use std::path::Path;
+
+/// Names accepted for the first column of the header, case insensitive
+const SAMPLE_COLUMNS: [&str; 4] = ["sample", "sample_id", "sample_name", "id"];
+
+/// Sample metadata, read from a `tab` separated file with a header. The first
+/// column is the sample ID, the others are variables like condition,
+/// timepoint or subject.
+#[derive(Debug, Default)]
+pub struct SampleMetadata {
+    pub columns: Vec<String>,
+    pub values: HashMap<String, HashMap<String, String>>,
+}
+
+impl SampleMetadata {
+    pub fn read_from_file<P: AsRef<Path>>(file_name: P) -> Result<Self> {
+        info!(
+            "Reading sample metadata file: {}",
+            file_name.as_ref().display()
+        );
+        let file_handle = bio_rascal::io::open_file(file_name).context("Cannot open file")?;
+        let metadata = Self::from_reader(file_handle)?;
+        info!("Number of samples in metadata: {}", metadata.values.len());
+
+        Ok(metadata)
+    }
+
+    /// Reads the metadata, the first column of the header must be one of
+    /// `SAMPLE_COLUMNS`, so a file without a header is not read as if its
+    /// first sample was the header
+    fn from_reader<R: BufRead>(reader: R) -> Result<Self> {
+        let mut metadata = SampleMetadata::default();
+
+        for line in reader.lines() {
+            let line = line.context("Problem parsing line")?;
+            if line.is_empty() || (line.starts_with('#') && !metadata.columns.is_empty()) {
+                continue;
+            }
+            let fields: Vec<&str> = line.trim_end().split('\t').collect();
+            if metadata.columns.is_empty() {
+                // the header can be a comment line
+                let first = fields[0].trim_start_matches('#');
+                metadata.columns = std::iter::once(first)
+                    .chain(fields[1..].iter().copied())
+                    .map(|f| f.trim().to_string())
+                    .collect();
+                if metadata.columns.len() < 2 {
+                    bail!("Expected at least 2 columns in the sample metadata header");
+                }
+                if !SAMPLE_COLUMNS
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&metadata.columns[0]))
+                {
+                    bail!(
+                        "The first column of the sample metadata header must be one of {}, got {}",
+                        SAMPLE_COLUMNS.join(", "),
+                        metadata.columns[0]
+                    );
+                }
+                continue;
+            }
+            if fields.len() != metadata.columns.len() {
+                bail!(
+                    "Expected {} columns for sample {}, got {}",
+                    metadata.columns.len(),
+                    fields[0],
+                    fields.len()
+                );
+            }
+            let values = metadata.columns[1..]
+                .iter()
+                .zip(fields[1..].iter())
+                .map(|(column, value)| (column.clone(), value.trim().to_string()))
+                .collect();
+            metadata.values.insert(fields[0].trim().to_string(), values);
+        }
+        if metadata.columns.is_empty() {
+            bail!("No header found in the sample metadata file");
+        }
+
+        Ok(metadata)
+    }
+
+    /// Returns the value of `column` for each sample in `sample_ids`, samples
+    /// not found in the metadata are skipped with a warning
+    pub fn get_groups<'a, I>(&self, column: &str, sample_ids: I) -> Result<HashMap<String, String>>
+    where
+        I: IntoIterator<Item = &'a String>,
+    {
+        if !self.columns[1..].iter().any(|c| c == column) {
+            bail!(
+                "Column {} not found in sample metadata, available: {}",
+                column,
+                self.columns[1..].join(", ")
+            );
+        }
+        let mut groups = HashMap::new();
+        for sample_id in sample_ids {
+            match self.values.get(sample_id).and_then(|v| v.get(column)) {
+                None => warn!("Sample {} not found in sample metadata", sample_id),
+                Some(group) => {
+                    groups.insert(sample_id.clone(), group.clone());
+                }
+            }
+        }
+        Ok(groups)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_reader() {
+        let data = "#Sample_ID\tcondition\n\nS1\tA\n# comment\nS2\tB\n";
+        let metadata = SampleMetadata::from_reader(data.as_bytes()).unwrap();
+        assert_eq!(metadata.columns, ["Sample_ID", "condition"]);
+        assert_eq!(metadata.values["S2"]["condition"], "B");
+        let samples = ["S1".to_string(), "S3".to_string()];
+        let groups = metadata.get_groups("condition", &samples).unwrap();
+        assert_eq!(groups, HashMap::from([("S1".to_string(), "A".to_string())]));
+        assert!(metadata.get_groups("time", &samples).is_err());
+
+        // no header, the first sample is not used as one
+        assert!(SampleMetadata::from_reader("S1\tA\nS2\tB\n".as_bytes()).is_err());
+        assert!(SampleMetadata::from_reader("sample\n".as_bytes()).is_err());
+        assert!(SampleMetadata::from_reader("sample\tcondition\nS1\n".as_bytes()).is_err());
+        assert!(SampleMetadata::from_reader("".as_bytes()).is_err());
+    }
+}
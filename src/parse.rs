@@ -1,4 +1,13 @@
-use super::cli::Parse;
+use super::cli::{Kappa, Parse};
+use super::codon::{codon_differences, is_transition, translate, CodingSequence, CodonChange};
+use super::data::{
+    check_output_file_name, save_parse_output, AnnotationInfo, AnnotationTable, ExtraCounts,
+    ParseOutput, SampleExtra,
+};
+use super::provenance::Provenance;
+use super::snp_table::{SnpRow, SnpTableWriter};
+use super::spectrum::MutationSpectrum;
+use super::vcf::{VcfLine, VcfLineReader, VcfWriter};
 use anyhow::{bail, Result};
 use bio_rascal::fasta::FastaReader;
 use bio_rascal::gff::{Annotation, GffReader};
@@ -7,16 +16,17 @@ use bio_rascal::sequence::SequenceRecord;
 use bio_rascal::snps::PnPs;
 use console::style;
 use indicatif::ProgressBar;
-use log::{error, info};
-use serde_json::to_writer;
+use log::{error, info, warn};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::{BufRead, BufReader};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use uuid::Uuid;
 
 pub type SampleInfo = HashMap<String, (String, String)>;
 pub type SamplePnPs = HashMap<String, HashMap<Uuid, PnPs>>;
+/// Number of transitions and transversions in each sample
+type SampleSubstitutions = HashMap<String, (u32, u32)>;
 
 fn read_config_file<P: AsRef<Path>>(file_name: P) -> Result<SampleInfo> {
     info!("Reading Config file: {}", file_name.as_ref().display());
@@ -65,10 +75,23 @@ fn read_fasta_file<P: AsRef<Path>>(file_name: P) -> Result<HashMap<String, Seque
     Ok(hm)
 }
 
+/// Expected sites of the annotation, weighted by κ if given
+fn get_expected_sites(
+    annotation: &Annotation,
+    record: &SequenceRecord,
+    kappa: Option<f64>,
+) -> (f64, f64) {
+    match kappa {
+        None => annotation.get_exp_syn(&record.seq),
+        Some(kappa) => CodingSequence::new(annotation, &record.seq).expected_sites(kappa),
+    }
+}
+
 /// For one sample
 fn prepare_annotations(
     annotations: &HashMap<Uuid, Annotation>,
     fasta_records: &HashMap<String, SequenceRecord>,
+    kappa: Option<f64>,
 ) -> Result<Vec<PnPs>> {
     let mut pnps_list: Vec<PnPs> = vec![];
     info!("Preparing pN/pS data");
@@ -79,7 +102,7 @@ fn prepare_annotations(
             None => bail!("Cannot find sequence for {}", &annotation.seq_id),
             Some(value) => value,
         };
-        let (exp_syn, exp_nonsyn) = annotation.get_exp_syn(&record.seq);
+        let (exp_syn, exp_nonsyn) = get_expected_sites(annotation, record, kappa);
         let pnps = PnPs {
             uid: annotation.uid,
             exp_syn,
@@ -157,15 +180,485 @@ fn add_depth_sample_data(
     Ok(pnps_map)
 }
 
+/// INFO fields added to the annotated VCF, one value for each alternative
+/// allele and overlapping CDS
+static ANNOTATED_VCF_HEADER: [&str; 6] = [
+    "##INFO=<ID=PNPS_ALT,Number=.,Type=Integer,Description=\"Index of the alternative allele (from 1) of the pnps-utils annotation\">",
+    "##INFO=<ID=PNPS_UID,Number=.,Type=String,Description=\"UID of the overlapping CDS\">",
+    "##INFO=<ID=PNPS_LOCUS_TAG,Number=.,Type=String,Description=\"Locus tag of the overlapping CDS\">",
+    "##INFO=<ID=PNPS_CODON,Number=.,Type=String,Description=\"Codon change on the coding strand (REF>ALT)\">",
+    "##INFO=<ID=PNPS_AA,Number=.,Type=String,Description=\"Amino acid change (REF>ALT), * is a stop codon\">",
+    "##INFO=<ID=PNPS_CLASS,Number=.,Type=String,Description=\"Class of the change: SYN, NONSYN, STOP_GAIN or STOP_LOSS\">",
+];
+
+/// Class of a SNP in a CDS, stop-gain and stop-loss SNPs are also
+/// nonsynonymous
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SnpClass {
+    Syn,
+    Nonsyn,
+    StopGain,
+    StopLoss,
+}
+
+impl SnpClass {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Self::Syn => "syn",
+            Self::Nonsyn => "nonsyn",
+            Self::StopGain => "stop_gain",
+            Self::StopLoss => "stop_loss",
+        }
+    }
+}
+
+/// Effect of an alternative allele on a CDS
+struct SnpEffect<'a> {
+    annotation: &'a Annotation,
+    /// Index of the allele in the VCF record, 0 is the reference
+    allele: usize,
+    /// Alternative base, on the forward strand
+    alt: u8,
+    is_syn: bool,
+    /// `None` if the codon cannot be determined
+    codon_change: Option<CodonChange>,
+}
+
+impl<'a> SnpEffect<'a> {
+    fn locus_tag(&self) -> Option<&str> {
+        self.annotation
+            .attributes
+            .get("locus_tag")
+            .map(|t| t.as_str())
+    }
+
+    fn class(&self) -> SnpClass {
+        match &self.codon_change {
+            _ if self.is_syn => SnpClass::Syn,
+            Some(change) if change.is_stop_gain() => SnpClass::StopGain,
+            Some(change) if change.is_stop_loss() => SnpClass::StopLoss,
+            _ => SnpClass::Nonsyn,
+        }
+    }
+}
+
+/// SNP of a sample in a CDS, kept until all the SNPs in the same codon are
+/// known
+struct CodonSnp {
+    pos: usize,
+    /// Alternative base, on the forward strand
+    alt: u8,
+    is_syn: bool,
+    class: SnpClass,
+}
+
+/// Sample ID, CDS UID and codon number, `None` if the codon cannot be
+/// determined
+type CodonKey = (String, Uuid, Option<usize>);
+
+/// Returns the reference and alternative codons for SNPs in the same codon,
+/// `None` if the codon cannot be determined or two SNPs are in the same
+/// position (different alleles in the sample)
+fn get_joint_codon_change(
+    snps: &[CodonSnp],
+    annotation: &Annotation,
+    fasta_records: &HashMap<String, SequenceRecord>,
+) -> Option<([u8; 3], [u8; 3])> {
+    let seqr = fasta_records.get(&annotation.seq_id)?;
+    let mut positions: Vec<usize> = snps.iter().map(|s| s.pos).collect();
+    positions.sort_unstable();
+    positions.dedup();
+    if positions.len() != snps.len() {
+        return None;
+    }
+    let changes: Vec<(usize, u8)> = snps.iter().map(|s| (s.pos, s.alt)).collect();
+    CodingSequence::new(annotation, &seqr.seq)
+        .apply_snps(&changes)
+        .ok()
+        .map(|(_, ref_codon, alt_codon)| (ref_codon, alt_codon))
+}
+
+/// Counts the SNPs in each sample, grouped by codon. SNPs in the same codon
+/// are classified by the change of the whole codon (see `codon_differences`)
+/// and count as one stop-gain or stop-loss. With pathway averaging the counts
+/// can be fractional, they are saved in the extra counts and rounded in
+/// `PnPs`. Returns the number of codons with more than one SNP.
+fn count_codon_snps(
+    codon_snps: HashMap<CodonKey, Vec<CodonSnp>>,
+    annotations: &HashMap<Uuid, Annotation>,
+    fasta_records: &HashMap<String, SequenceRecord>,
+    pnps_map: &mut SamplePnPs,
+    extra_map: &mut SampleExtra,
+    pathway_averaging: bool,
+) -> u32 {
+    let mut totals: HashMap<(String, Uuid), (f64, f64)> = HashMap::new();
+    let mut multiple_snps = 0u32;
+    for ((sample_id, uid, codon_number), snps) in codon_snps {
+        let joint_change = match (codon_number, annotations.get(&uid)) {
+            (Some(_), Some(annotation)) if snps.len() > 1 => {
+                get_joint_codon_change(&snps, annotation, fasta_records)
+            }
+            _ => None,
+        };
+        let (mut syn, mut nonsyn) = (0., 0.);
+        let mut classes: Vec<SnpClass> = vec![];
+        match joint_change {
+            Some((ref_codon, alt_codon)) => {
+                multiple_snps += 1;
+                (syn, nonsyn) = codon_differences(&ref_codon, &alt_codon, pathway_averaging);
+                match (translate(&ref_codon), translate(&alt_codon)) {
+                    (ref_aa, b'*') if ref_aa != b'*' => classes.push(SnpClass::StopGain),
+                    (b'*', alt_aa) if alt_aa != b'*' => classes.push(SnpClass::StopLoss),
+                    _ => {}
+                }
+            }
+            None => {
+                for snp in snps.iter() {
+                    if snp.is_syn {
+                        syn += 1.;
+                    } else {
+                        nonsyn += 1.;
+                    }
+                    classes.push(snp.class);
+                }
+            }
+        }
+        if let Some(extra) = extra_map.get_mut(&sample_id).and_then(|m| m.get_mut(&uid)) {
+            for class in classes {
+                match class {
+                    SnpClass::StopGain => extra.stop_gain += 1,
+                    SnpClass::StopLoss => extra.stop_loss += 1,
+                    _ => {}
+                }
+            }
+        }
+        let total = totals.entry((sample_id, uid)).or_default();
+        total.0 += syn;
+        total.1 += nonsyn;
+    }
+
+    for ((sample_id, uid), (syn, nonsyn)) in totals {
+        if let Some(pnps) = pnps_map.get_mut(&sample_id).and_then(|m| m.get_mut(&uid)) {
+            pnps.syn = (pnps.syn as f64 + syn).round() as _;
+            pnps.nonsyn = (pnps.nonsyn as f64 + nonsyn).round() as _;
+        }
+        if pathway_averaging {
+            if let Some(extra) = extra_map.get_mut(&sample_id).and_then(|m| m.get_mut(&uid)) {
+                extra.fractional_syn = Some(extra.fractional_syn.unwrap_or_default() + syn);
+                extra.fractional_nonsyn =
+                    Some(extra.fractional_nonsyn.unwrap_or_default() + nonsyn);
+            }
+        }
+    }
+    multiple_snps
+}
+
+/// Adds the nucleotide diversity at the site to each CDS with an effect, for
+/// the samples with allele depths (`AD`). The diversity is `1 - Σf²` over the
+/// allele frequencies, corrected by `D / (D - 1)` for the depth `D`, and each
+/// pair of alleles is assigned to the synonymous or nonsynonymous part.
+/// With `sfs_bins`, the site is also added to the folded site frequency
+/// spectrum, by the frequency of the second most common allele, classified by
+/// its difference to the most common one. Returns the number of samples
+/// without allele depths.
+fn add_diversity(
+    record: &VcfLine,
+    effects: &[SnpEffect],
+    sample_ids: &[Option<String>],
+    pnps_map: &SamplePnPs,
+    extra_map: &mut SampleExtra,
+    sfs_bins: Option<usize>,
+) -> u32 {
+    let mut uids: Vec<Uuid> = effects.iter().map(|e| e.annotation.uid).collect();
+    uids.sort_unstable();
+    uids.dedup();
+
+    let mut missing_depths = 0u32;
+    for (sample_index, sample_id) in sample_ids.iter().enumerate() {
+        let sample_id = match sample_id {
+            None => continue,
+            Some(value) => value,
+        };
+        let sample_pnps_map = match pnps_map.get(sample_id) {
+            None => continue,
+            Some(value) => value,
+        };
+        let depths = match record.allele_depths(sample_index) {
+            None => {
+                missing_depths += 1;
+                continue;
+            }
+            Some(value) => value,
+        };
+        let total: u32 = depths.iter().sum();
+        if total < 2 {
+            continue;
+        }
+        let correction = total as f64 / (total - 1) as f64;
+        let freqs: Vec<f64> = depths.iter().map(|d| *d as f64 / total as f64).collect();
+        let pi = correction * (1. - freqs.iter().map(|f| f * f).sum::<f64>());
+        if pi <= 0. {
+            continue;
+        }
+        // bin and the two most common alleles, in allele order
+        let sfs_site = sfs_bins.map(|n_bins| {
+            let mut alleles: Vec<usize> = (0..freqs.len()).collect();
+            alleles.sort_by(|a, b| freqs[*b].total_cmp(&freqs[*a]));
+            let bin = ((freqs[alleles[1]] * 2. * n_bins as f64).ceil() as usize).clamp(1, n_bins);
+            let pair = (alleles[0].min(alleles[1]), alleles[0].max(alleles[1]));
+            (bin - 1, pair)
+        });
+
+        for uid in uids.iter() {
+            if !sample_pnps_map.contains_key(uid) {
+                continue;
+            }
+            let extra = match extra_map.get_mut(sample_id).and_then(|m| m.get_mut(uid)) {
+                None => continue,
+                Some(value) => value,
+            };
+            extra.pi += pi;
+            extra.segregating_sites += 1;
+            let effect = |allele: usize| {
+                effects
+                    .iter()
+                    .find(|e| e.annotation.uid == *uid && e.allele == allele)
+            };
+            // for alleles i < j, pairs of alternative alleles compare their
+            // amino acids
+            let is_syn = |i: usize, j: usize| match (i, effect(i), effect(j)) {
+                (0, _, Some(e)) => Some(e.is_syn),
+                (_, Some(a), Some(b)) => match (&a.codon_change, &b.codon_change) {
+                    (Some(a), Some(b)) => Some(a.alt_aa() == b.alt_aa()),
+                    _ => None,
+                },
+                _ => None,
+            };
+            for i in 0..freqs.len() {
+                for j in (i + 1)..freqs.len() {
+                    let pair_pi = correction * 2. * freqs[i] * freqs[j];
+                    if pair_pi == 0. {
+                        continue;
+                    }
+                    match is_syn(i, j) {
+                        Some(true) => extra.pi_syn += pair_pi,
+                        Some(false) => extra.pi_nonsyn += pair_pi,
+                        None => {}
+                    }
+                }
+            }
+            if let Some((bin, (i, j))) = sfs_site {
+                let sfs = match is_syn(i, j) {
+                    None => continue,
+                    Some(true) => &mut extra.sfs_syn,
+                    Some(false) => &mut extra.sfs_nonsyn,
+                };
+                if let Some(count) = sfs.get_mut(bin) {
+                    *count += 1;
+                }
+            }
+        }
+    }
+    missing_depths
+}
+
+/// Returns the effect of each alternative allele (SNPs only) on the CDS
+/// overlapping the record
+fn get_snp_effects<'a>(
+    record: &VcfLine,
+    ann: &[&'a Annotation],
+    fasta_records: &HashMap<String, SequenceRecord>,
+    pb: &ProgressBar,
+) -> Vec<SnpEffect<'a>> {
+    let mut effects = vec![];
+    for a in ann.iter().filter(|a| a.contains(record.pos)) {
+        if let Some(seqr) = fasta_records.get(&a.seq_id) {
+            let cds = CodingSequence::new(a, &seqr.seq);
+            for (index, alt) in record.alt_alleles.iter().enumerate() {
+                if alt.starts_with('<') || alt == "*" {
+                    continue;
+                }
+                match a.is_syn(&seqr.seq, record.pos, alt) {
+                    Ok(is_syn) => effects.push(SnpEffect {
+                        annotation: a,
+                        allele: index + 1,
+                        alt: alt.as_bytes()[0],
+                        is_syn,
+                        codon_change: cds.codon_change(record.pos, alt.as_bytes()[0]).ok(),
+                    }),
+                    Err(err) => pb.println(style(err).red().to_string()),
+                }
+            }
+        }
+    }
+    effects
+}
+
+/// Counts the in-frame and frameshift indels in the CDS, for each sample.
+/// An indel is assigned to all the CDS overlapping the bases it affects,
+/// after the anchor base at `POS`: the deleted bases, or the base following
+/// an insertion.
+fn count_indels(
+    record: &VcfLine,
+    ann: &[&Annotation],
+    sample_ids: &[Option<String>],
+    extra_map: &mut SampleExtra,
+    sample_indels: &mut HashMap<String, u32>,
+) {
+    let ref_len = record.ref_allele.len();
+    let start = record.pos + 1;
+    let end = record.pos + ref_len.saturating_sub(1).max(1);
+    let overlapping: Vec<&&Annotation> = ann
+        .iter()
+        .filter(|a| a.start as usize <= end && a.end as usize >= start)
+        .collect();
+    for (sample_index, allele) in record.sample_alt_alleles() {
+        let sample_id = match &sample_ids[sample_index] {
+            None => continue,
+            Some(value) => value,
+        };
+        let alt_len = match record.allele(allele) {
+            // SNP allele in the same record
+            Some(alt) if alt.len() == ref_len => continue,
+            Some(alt) => alt.len(),
+            None => continue,
+        };
+        *sample_indels.entry(sample_id.clone()).or_default() += 1;
+        let frameshift = alt_len.abs_diff(ref_len) % 3 != 0;
+        if let Some(sample_extra) = extra_map.get_mut(sample_id) {
+            for a in overlapping.iter() {
+                if let Some(extra) = sample_extra.get_mut(&a.uid) {
+                    if frameshift {
+                        extra.frameshift_indel += 1;
+                    } else {
+                        extra.inframe_indel += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the `INFO` entries for the annotated VCF
+fn get_info_entries(effects: &[SnpEffect]) -> Vec<String> {
+    if effects.is_empty() {
+        return vec![];
+    }
+    let entry = |key: &str, value: &dyn Fn(&SnpEffect) -> String| {
+        let values: Vec<String> = effects.iter().map(value).collect();
+        format!("{}={}", key, values.join(","))
+    };
+    vec![
+        entry("PNPS_ALT", &|e| e.allele.to_string()),
+        entry("PNPS_UID", &|e| e.annotation.uid.to_string()),
+        entry("PNPS_LOCUS_TAG", &|e| {
+            e.locus_tag().unwrap_or(".").to_string()
+        }),
+        entry("PNPS_CODON", &|e| match &e.codon_change {
+            None => ".".to_string(),
+            Some(c) => format!("{}>{}", c.ref_codon_string(), c.alt_codon_string()),
+        }),
+        entry("PNPS_AA", &|e| match &e.codon_change {
+            None => ".".to_string(),
+            Some(c) => format!("{}>{}", c.ref_aa() as char, c.alt_aa() as char),
+        }),
+        entry("PNPS_CLASS", &|e| e.class().as_str().to_uppercase()),
+    ]
+}
+
+/// κ as twice the ratio of transitions to transversions, because each base
+/// has one transition and two transversions. `None` if either is missing.
+fn estimate_kappa(transitions: u32, transversions: u32) -> Option<f64> {
+    match (transitions, transversions) {
+        (0, _) | (_, 0) => None,
+        _ => Some(2. * transitions as f64 / transversions as f64),
+    }
+}
+
+/// Weights the expected sites of each sample by κ, estimated from its SNPs.
+/// Samples where κ cannot be estimated keep the unweighted sites.
+fn weight_expected_sites(
+    pnps_map: &mut SamplePnPs,
+    extra_map: &mut SampleExtra,
+    substitutions: &SampleSubstitutions,
+    annotations: &HashMap<Uuid, Annotation>,
+    fasta_records: &HashMap<String, SequenceRecord>,
+    provenance: &mut Provenance,
+) {
+    let mut sample_ids: Vec<String> = pnps_map.keys().cloned().collect();
+    sample_ids.sort();
+    for sample_id in sample_ids {
+        let (transitions, transversions) =
+            substitutions.get(&sample_id).copied().unwrap_or_default();
+        let kappa = match estimate_kappa(transitions, transversions) {
+            None => {
+                warn!(
+                    "Cannot estimate kappa for sample {} ({} transitions, {} transversions), expected sites are not weighted",
+                    style(&sample_id).yellow(),
+                    transitions,
+                    transversions
+                );
+                continue;
+            }
+            Some(value) => value,
+        };
+        info!(
+            "Sample {}: {} transitions, {} transversions, kappa {:.3}",
+            sample_id, transitions, transversions, kappa
+        );
+        provenance.add_parameter(&format!("kappa:{}", sample_id), kappa);
+        for (uid, pnps) in pnps_map.get_mut(&sample_id).unwrap().iter_mut() {
+            let annotation = match annotations.get(uid) {
+                None => continue,
+                Some(value) => value,
+            };
+            if let Some(record) = fasta_records.get(&annotation.seq_id) {
+                (pnps.exp_syn, pnps.exp_nonsyn) =
+                    get_expected_sites(annotation, record, Some(kappa));
+            }
+        }
+        for extra in extra_map.entry(sample_id).or_default().values_mut() {
+            extra.kappa = Some(kappa);
+        }
+    }
+}
+
+/// Options used to read the VCF file
+struct VcfOptions<'a> {
+    // filters
+    min_qual: f64,
+    min_depth: u32,
+    // counts
+    pathway_averaging: bool,
+    sfs_bins: Option<usize>,
+    // optional outputs
+    snp_table: Option<&'a PathBuf>,
+    annotated_vcf: Option<&'a PathBuf>,
+    mutation_spectrum: Option<&'a PathBuf>,
+    spectrum_context: bool,
+}
+
+/// Returns the number of transitions and transversions in each sample, for
+/// the SNPs passing the filters
 fn parse_vcf_file<P: AsRef<Path>>(
     file_name: P,
     pnps_map: &mut SamplePnPs,
+    extra_map: &mut SampleExtra,
     fasta_records: &HashMap<String, SequenceRecord>,
     annotations: &HashMap<Uuid, Annotation>,
     sample_info: &SampleInfo,
-    min_qual: f64,
-    min_depth: u32
-) -> Result<()> {
+    options: VcfOptions,
+) -> Result<SampleSubstitutions> {
+    let VcfOptions {
+        min_qual,
+        min_depth,
+        pathway_averaging,
+        sfs_bins,
+        snp_table,
+        annotated_vcf,
+        mutation_spectrum,
+        spectrum_context,
+    } = options;
     info!("Preparing annotations");
     let mut ann_seq: HashMap<&String, Vec<&Annotation>> = HashMap::new();
     for annotation in annotations.values() {
@@ -175,83 +668,269 @@ fn parse_vcf_file<P: AsRef<Path>>(
             .or_insert(vec![annotation]);
     }
 
-    let vcf_reader = bio_rascal::snps::VcfReader::new(file_name)?;
+    let vcf_reader = VcfLineReader::new(file_name)?;
     info!("Number of VCF samples: {}", vcf_reader.sample_names.len());
+    // sample ID in the config file for each sample column
+    let sample_ids: Vec<Option<String>> = vcf_reader
+        .sample_names
+        .iter()
+        .map(|sample_name| match sample_info.get(sample_name) {
+            None => {
+                error!("Cannot find the sample {sample_name}");
+                None
+            }
+            Some(value) => Some(value.0.clone()),
+        })
+        .collect();
+
+    let mut snp_table = match snp_table {
+        None => None,
+        Some(file_name) => {
+            info!("Writing SNP table to {}", file_name.display());
+            Some(SnpTableWriter::new(file_name)?)
+        }
+    };
+    let mut annotated_vcf = match annotated_vcf {
+        None => None,
+        Some(file_name) => {
+            info!("Writing annotated VCF to {}", file_name.display());
+            let extra_header: Vec<String> =
+                ANNOTATED_VCF_HEADER.iter().map(|h| h.to_string()).collect();
+            Some(VcfWriter::new(file_name, &vcf_reader, &extra_header)?)
+        }
+    };
+
+    let mut spectrum = mutation_spectrum.map(|_| MutationSpectrum::new(spectrum_context));
+    // SNPs that cannot be added to the spectrum, e.g. without context
+    let mut unclassified_spectrum = 0u32;
 
     let pb = indicatif::ProgressBar::new_spinner().with_message("VCF Reading");
     let mut count = 0u32;
     let mut skipped_dp = 0u32;
     let mut skipped_indel = 0u32;
     let mut skipped_qual = 0u32;
+    // indel calls in each sample, not used for pN/pS
+    let mut sample_indels: HashMap<String, u32> = HashMap::new();
+    // SNPs are counted after reading the file, grouped by codon
+    let mut codon_snps: HashMap<CodonKey, Vec<CodonSnp>> = HashMap::new();
+    let mut substitutions = SampleSubstitutions::new();
+    // sample columns without allele depths, for sites in CDS
+    let mut missing_depths = 0u32;
 
     for record in vcf_reader {
+        let record = record?;
         pb.inc(1);
         count += 1;
-        if record.info.dp < min_depth {
+        let skip = if record.depth() < min_depth {
             skipped_dp += 1;
-            continue;
-        } else if record.qual < min_qual {
+            true
+        } else if record.qual < min_qual || (record.qual.is_nan() && min_qual > 0.) {
             skipped_qual += 1;
-            continue;
-        } else if record.info.indel || record.ref_c.len() > 1 {
+            true
+        } else if record.is_indel() {
             skipped_indel += 1;
-            continue;
-        }
+            let ann = ann_seq.get(&record.chrom).map(|a| a.as_slice());
+            count_indels(
+                &record,
+                ann.unwrap_or_default(),
+                &sample_ids,
+                extra_map,
+                &mut sample_indels,
+            );
+            true
+        } else {
+            false
+        };
 
-        let ann = match ann_seq.get(&record.chrom) {
-            None => {
-                //error!("{}", record.chrom);
-                continue;
-            }
-            Some(value) => value.iter().filter(|a| a.contains(record.pos)), //.collect()
+        let effects = match ann_seq.get(&record.chrom) {
+            Some(ann) if !skip => get_snp_effects(&record, ann, fasta_records, &pb),
+            _ => vec![],
         };
-        for a in ann {
-            if let Some(seqr) = fasta_records.get(&a.seq_id) {
-                for (sample_id, alt) in record.get_sample_snps() {
-                    let sample_id = match sample_info.get(&sample_id) {
-                        None => {
-                            error!("Cannot find the sample {sample_id}");
-                            continue;
-                        }
-                        Some(value) => &value.0,
-                    };
-                    if let Some(sample_pnps_map) = pnps_map.get_mut(sample_id) {
-                        if let Some(mut sample_pnps) = sample_pnps_map.get_mut(&a.uid) {
-                            match a.is_syn(&seqr.seq, record.pos, &alt) {
-                                Ok(is_syn) => {
-                                    if is_syn {
-                                        sample_pnps.syn += 1;
-                                    } else {
-                                        sample_pnps.nonsyn += 1;
-                                    }
-                                },
-                                Err(err) => pb.println(style(err).red().to_string()),
-                            }
-                        }
+        if !effects.is_empty() {
+            missing_depths += add_diversity(
+                &record,
+                &effects,
+                &sample_ids,
+                pnps_map,
+                extra_map,
+                sfs_bins,
+            );
+        }
+        for (sample_index, allele) in record.sample_alt_alleles() {
+            let sample_id = match &sample_ids[sample_index] {
+                None => continue,
+                Some(value) => value,
+            };
+            let transition = match record.allele(allele) {
+                Some(alt) if !skip => {
+                    is_transition(record.ref_allele.as_bytes()[0], alt.as_bytes()[0])
+                }
+                _ => None,
+            };
+            if let Some(transition) = transition {
+                let counts = substitutions.entry(sample_id.clone()).or_default();
+                match transition {
+                    true => counts.0 += 1,
+                    false => counts.1 += 1,
+                }
+            }
+            if let (Some(spectrum), Some(alt), false) =
+                (spectrum.as_mut(), record.allele(allele), skip)
+            {
+                let coding = match ann_seq.get(&record.chrom) {
+                    None => false,
+                    Some(ann) => ann
+                        .iter()
+                        .any(|a| record.pos >= a.start as usize && record.pos <= a.end as usize),
+                };
+                // 5' and 3' bases, on the forward strand
+                let flanks = match fasta_records.get(&record.chrom) {
+                    Some(seqr) if spectrum_context && record.pos > 1 => {
+                        let seq: &[u8] = seqr.seq.as_ref();
+                        seq.get(record.pos - 2).zip(seq.get(record.pos))
                     }
-                    //info!("{} -> {}", sample_id, alt);
+                    _ => None,
+                };
+                let added = spectrum.add(
+                    sample_id,
+                    coding,
+                    record.ref_allele.as_bytes()[0],
+                    alt.as_bytes()[0],
+                    flanks.map(|(a, b)| (*a, *b)),
+                );
+                if !added {
+                    unclassified_spectrum += 1;
                 }
             }
+            let sample_pnps_map = match pnps_map.get(sample_id) {
+                None => continue,
+                Some(value) => value,
+            };
+            for effect in effects.iter().filter(|e| e.allele == allele) {
+                if sample_pnps_map.contains_key(&effect.annotation.uid) {
+                    let codon_number = effect.codon_change.as_ref().map(|c| c.codon_number);
+                    codon_snps
+                        .entry((sample_id.clone(), effect.annotation.uid, codon_number))
+                        .or_default()
+                        .push(CodonSnp {
+                            pos: record.pos,
+                            alt: effect.alt,
+                            is_syn: effect.is_syn,
+                            class: effect.class(),
+                        });
+                    if let Some(writer) = snp_table.as_mut() {
+                        let codon_change = effect.codon_change.as_ref();
+                        writer.write(&SnpRow {
+                            sample: sample_id,
+                            contig: &record.chrom,
+                            position: record.pos,
+                            ref_allele: &record.ref_allele,
+                            alt: record.allele(allele).unwrap_or_default(),
+                            uid: effect.annotation.uid,
+                            locus_tag: effect.locus_tag(),
+                            codon_number: codon_change.map(|c| c.codon_number),
+                            codon_position: codon_change.map(|c| c.codon_position),
+                            ref_codon: codon_change.map(|c| c.ref_codon_string()),
+                            alt_codon: codon_change.map(|c| c.alt_codon_string()),
+                            ref_aa: codon_change.map(|c| c.ref_aa() as char),
+                            alt_aa: codon_change.map(|c| c.alt_aa() as char),
+                            class: effect.class().as_str(),
+                            depth: record.sample_depth(sample_index),
+                            allele_frequency: record.allele_frequency(sample_index, allele),
+                        })?;
+                    }
+                }
+            }
+        }
+        if let Some(writer) = annotated_vcf.as_mut() {
+            writer.write_line(&record.line_with_info(&get_info_entries(&effects)))?;
+        }
+    }
+
+    if let Some(writer) = snp_table {
+        writer.finish()?;
+    }
+    if let Some(writer) = annotated_vcf {
+        writer.finish()?;
+    }
+    if let (Some(spectrum), Some(file_name)) = (spectrum, mutation_spectrum) {
+        info!("Writing mutation spectrum to {}", file_name.display());
+        if unclassified_spectrum > 0 {
+            warn!(
+                "SNPs not in the mutation spectrum (other bases or no context): {}",
+                unclassified_spectrum
+            );
         }
-        //info!("{} -> {}, {}", record.chrom, ann.len(), record.info.ac.len());
+        spectrum.write(file_name)?;
+    }
+
+    if pathway_averaging {
+        for extra in extra_map.values_mut().flat_map(|m| m.values_mut()) {
+            extra.fractional_syn = Some(0.);
+            extra.fractional_nonsyn = Some(0.);
+        }
+    }
+    let multiple_snps = count_codon_snps(
+        codon_snps,
+        annotations,
+        fasta_records,
+        pnps_map,
+        extra_map,
+        pathway_averaging,
+    );
+    info!("Codons with multiple SNPs in a sample: {}", multiple_snps);
+    if missing_depths > 0 {
+        warn!(
+            "Samples without allele depths (AD) at sites in CDS, not used for nucleotide diversity: {}",
+            missing_depths
+        );
     }
 
     info!(
         "VCF records {count}, Skipped INDEL: {skipped_indel}, Skipped for low QUAL: {skipped_qual}, Skipped for low DP (depth) {:.2}%",
         skipped_dp as f64 / count as f64 * 100f64
     );
+    let mut indel_samples: Vec<&String> = sample_indels.keys().collect();
+    indel_samples.sort();
+    for sample_id in indel_samples {
+        let (inframe, frameshift) = extra_map
+            .get(sample_id)
+            .map(|m| {
+                m.values().fold((0, 0), |acc, e| {
+                    (acc.0 + e.inframe_indel, acc.1 + e.frameshift_indel)
+                })
+            })
+            .unwrap_or_default();
+        info!(
+            "Skipped INDEL in sample {}: {}, in CDS {} in-frame and {} frameshift",
+            sample_id, sample_indels[sample_id], inframe, frameshift
+        );
+    }
 
-    Ok(())
+    Ok(substitutions)
 }
 
 pub fn parse_command(options: Parse) -> Result<()> {
     let output_file = match options.output_file {
-        None => File::create("pnps.json")?,
-        Some(value) => File::create(value)?,
+        None => PathBuf::from("pnps.bin"),
+        Some(value) => value,
     };
+    check_output_file_name(&output_file)?;
     
     info!("Minimum Depth {}, Qual {}, Coverage {}", options.min_depth, options.min_qual, options.min_coverage);
 
+    let mut provenance = Provenance::new("parse");
+    provenance.add_parameter("min_depth", options.min_depth);
+    provenance.add_parameter("min_qual", options.min_qual);
+    provenance.add_parameter("min_coverage", options.min_coverage);
+    provenance.add_parameter("pathway_averaging", options.pathway_averaging);
+    if let Some(kappa) = options.kappa {
+        provenance.add_parameter("kappa", kappa);
+    }
+    if let Some(sfs_bins) = options.sfs_bins {
+        provenance.add_parameter("sfs_bins", sfs_bins);
+    }
+
     // starts reading the GFF file
     let annotations = read_gff_file(&options.gff_file)?;
     info!("Number of Annotations: {}", annotations.len());
@@ -259,21 +938,381 @@ pub fn parse_command(options: Parse) -> Result<()> {
     info!("Number of Samples in Config file: {}", sample_info.len());
     let fasta_records = read_fasta_file(&options.fasta_file)?;
     info!("Number of Fasta records: {}", fasta_records.len());
-    let pnps_list = prepare_annotations(&annotations, &fasta_records)?;
+    provenance.add_input_file("config_file", &options.config_file)?;
+    provenance.add_input_file("gff_file", &options.gff_file)?;
+    provenance.add_input_file("fasta_file", &options.fasta_file)?;
+    provenance.add_input_file("vcf_file", &options.vcf_file)?;
+    for (sample_id, depth_file) in sample_info.values() {
+        provenance.add_input_file(&format!("depth_file:{}", sample_id), depth_file)?;
+    }
+    let kappa = match options.kappa {
+        Some(Kappa::Value(value)) => Some(value),
+        _ => None,
+    };
+    let pnps_list = prepare_annotations(&annotations, &fasta_records, kappa)?;
+    let sfs_bins = options.sfs_bins.map(usize::from);
 
     let mut pnps_map =
         add_depth_sample_data(&sample_info, &pnps_list, &annotations, options.min_coverage)?;
-    parse_vcf_file(
+    // same genes as the pN/pS data
+    let mut extra_map: SampleExtra = pnps_map
+        .iter()
+        .map(|(sample_id, sample_map)| {
+            let counts = sample_map
+                .keys()
+                .map(|uid| {
+                    let sfs = vec![0; sfs_bins.unwrap_or_default()];
+                    let counts = ExtraCounts {
+                        kappa,
+                        sfs_syn: sfs.clone(),
+                        sfs_nonsyn: sfs,
+                        ..Default::default()
+                    };
+                    (*uid, counts)
+                })
+                .collect();
+            (sample_id.clone(), counts)
+        })
+        .collect();
+    let substitutions = parse_vcf_file(
         options.vcf_file,
         &mut pnps_map,
+        &mut extra_map,
         &fasta_records,
         &annotations,
         &sample_info,
-        options.min_qual,
-        options.min_depth
+        VcfOptions {
+            min_qual: options.min_qual,
+            min_depth: options.min_depth,
+            pathway_averaging: options.pathway_averaging,
+            sfs_bins,
+            snp_table: options.snp_table.as_ref(),
+            annotated_vcf: options.annotated_vcf.as_ref(),
+            mutation_spectrum: options.mutation_spectrum.as_ref(),
+            spectrum_context: options.spectrum_context,
+        },
     )?;
+    if options.kappa == Some(Kappa::Estimate) {
+        weight_expected_sites(
+            &mut pnps_map,
+            &mut extra_map,
+            &substitutions,
+            &annotations,
+            &fasta_records,
+            &mut provenance,
+        );
+    }
 
-    to_writer(output_file, &pnps_map)?;
+    let annotation_table: AnnotationTable = annotations
+        .values()
+        .map(|a| (a.uid, AnnotationInfo::from_annotation(a)))
+        .collect();
+
+    info!("Writing output");
+    let parse_output = ParseOutput {
+        provenance: vec![provenance],
+        annotations: annotation_table,
+        samples: pnps_map,
+        extra: extra_map,
+    };
+    save_parse_output(&output_file, parse_output)?;
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Contig with one CDS (`gene_1`, 4-15), `ATG TGG CTT TAA`
+    static CONTIG: &str = "CCCATGTGGCTTTAAGGGGG";
+
+    /// Reads the annotations and the sequence from files, as `parse` does
+    fn test_data(name: &str) -> (HashMap<Uuid, Annotation>, HashMap<String, SequenceRecord>) {
+        // the tests run in parallel and may use the same name
+        static COUNTER: AtomicUsize = AtomicUsize::new(0);
+        let base_name = std::env::temp_dir().join(format!(
+            "pnps-utils-test-{}-{}-{}",
+            name,
+            std::process::id(),
+            COUNTER.fetch_add(1, Ordering::Relaxed)
+        ));
+        let gff_file = base_name.with_extension("gff");
+        let fasta_file = base_name.with_extension("fasta");
+        std::fs::write(
+            &gff_file,
+            "##gff-version 3\n\
+             contig_1\ttest\tgene\t4\t15\t.\t+\t.\tID=gene_1\n\
+             contig_1\ttest\tCDS\t4\t15\t.\t+\t0\tID=cds_1;locus_tag=gene_1\n",
+        )
+        .unwrap();
+        std::fs::write(&fasta_file, format!(">contig_1\n{}\n", CONTIG)).unwrap();
+        let annotations = read_gff_file(&gff_file);
+        let fasta_records = read_fasta_file(&fasta_file);
+        std::fs::remove_file(gff_file).unwrap();
+        std::fs::remove_file(fasta_file).unwrap();
+        (annotations.unwrap(), fasta_records.unwrap())
+    }
+
+    /// Empty counts for the CDS in each sample
+    fn sample_maps(uid: Uuid, sample_ids: &[&str]) -> (SamplePnPs, SampleExtra) {
+        let pnps_map = sample_ids
+            .iter()
+            .map(|s| {
+                let pnps = PnPs {
+                    uid,
+                    ..Default::default()
+                };
+                (s.to_string(), HashMap::from([(uid, pnps)]))
+            })
+            .collect();
+        let extra_map = sample_ids
+            .iter()
+            .map(|s| {
+                (
+                    s.to_string(),
+                    HashMap::from([(uid, ExtraCounts::default())]),
+                )
+            })
+            .collect();
+        (pnps_map, extra_map)
+    }
+
+    fn codon_snp(pos: usize, alt: u8, class: SnpClass) -> CodonSnp {
+        CodonSnp {
+            pos,
+            alt,
+            is_syn: class == SnpClass::Syn,
+            class,
+        }
+    }
+
+    fn codon_snps(uid: Uuid) -> HashMap<CodonKey, Vec<CodonSnp>> {
+        let key = |sample_id: &str, codon: usize| (sample_id.to_string(), uid, Some(codon));
+        HashMap::from([
+            // ATG > ATA
+            (key("S1", 1), vec![codon_snp(6, b'A', SnpClass::Nonsyn)]),
+            // TGG > TAA, two stop-gain SNPs but one stop codon
+            (
+                key("S1", 2),
+                vec![
+                    codon_snp(8, b'A', SnpClass::StopGain),
+                    codon_snp(9, b'A', SnpClass::StopGain),
+                ],
+            ),
+            // CTT (Leu) > TTA (Leu), through TTT (Phe) or CTA (Leu)
+            (
+                key("S1", 3),
+                vec![
+                    codon_snp(10, b'T', SnpClass::Nonsyn),
+                    codon_snp(12, b'A', SnpClass::Syn),
+                ],
+            ),
+            // two alleles at the same position, TGA and TGC
+            (
+                key("S2", 2),
+                vec![
+                    codon_snp(9, b'A', SnpClass::StopGain),
+                    codon_snp(9, b'C', SnpClass::Nonsyn),
+                ],
+            ),
+            // ATG > TTA, 0.5 synonymous and 1.5 nonsynonymous differences
+            (
+                key("S3", 1),
+                vec![
+                    codon_snp(4, b'T', SnpClass::Nonsyn),
+                    codon_snp(6, b'A', SnpClass::Nonsyn),
+                ],
+            ),
+        ])
+    }
+
+    /// Record with the genotypes of two samples
+    fn vcf_line(pos: usize, ref_allele: &str, alt: &str, genotypes: [&str; 2]) -> VcfLine {
+        VcfLine::from_line(format!(
+            "contig_1\t{}\t.\t{}\t{}\t50\t.\tDP=20\tGT\t{}\t{}",
+            pos, ref_allele, alt, genotypes[0], genotypes[1]
+        ))
+        .unwrap()
+    }
+
+    /// In-frame and frameshift indels in the CDS, for each sample
+    fn indel_counts(records: &[VcfLine]) -> (Vec<(u32, u32)>, HashMap<String, u32>) {
+        let (annotations, _) = test_data("count_indels");
+        let uid = *annotations.keys().next().unwrap();
+        let (_, mut extra_map) = sample_maps(uid, &["S1", "S2"]);
+        let ann: Vec<&Annotation> = annotations.values().collect();
+        let sample_ids = [Some("S1".to_string()), Some("S2".to_string())];
+        let mut sample_indels = HashMap::new();
+        for record in records {
+            count_indels(
+                record,
+                &ann,
+                &sample_ids,
+                &mut extra_map,
+                &mut sample_indels,
+            );
+        }
+        let counts = ["S1", "S2"]
+            .iter()
+            .map(|s| {
+                let extra = &extra_map[*s][&uid];
+                (extra.inframe_indel, extra.frameshift_indel)
+            })
+            .collect();
+        (counts, sample_indels)
+    }
+
+    #[test]
+    fn test_count_indels_boundaries() {
+        // insertion between the base before the CDS and its first base
+        let (counts, _) = indel_counts(&[vcf_line(3, "C", "CT", ["0/1", "0/0"])]);
+        assert_eq!(counts, [(0, 1), (0, 0)]);
+        // insertion after the last base of the CDS
+        let (counts, sample_indels) = indel_counts(&[vcf_line(15, "A", "AT", ["0/1", "0/0"])]);
+        assert_eq!(counts, [(0, 0), (0, 0)]);
+        assert_eq!(sample_indels["S1"], 1);
+        // deletion of the first codon, anchored before the CDS
+        let (counts, _) = indel_counts(&[vcf_line(3, "CATG", "C", ["1/1", "0/0"])]);
+        assert_eq!(counts, [(1, 0), (0, 0)]);
+        // deletion after the CDS, anchored on its last base
+        let (counts, _) = indel_counts(&[vcf_line(15, "AGG", "A", ["0/1", "0/0"])]);
+        assert_eq!(counts, [(0, 0), (0, 0)]);
+        // deletion of the last base of the CDS
+        let (counts, _) = indel_counts(&[vcf_line(14, "AA", "A", ["0/1", "0/0"])]);
+        assert_eq!(counts, [(0, 1), (0, 0)]);
+    }
+
+    #[test]
+    fn test_count_indels_mixed_alleles() {
+        // a SNP and an in-frame insertion in the same record
+        let (counts, sample_indels) = indel_counts(&[vcf_line(10, "C", "T,CAAA", ["0/1", "0/2"])]);
+        assert_eq!(counts, [(0, 0), (1, 0)]);
+        assert!(!sample_indels.contains_key("S1"));
+        assert_eq!(sample_indels["S2"], 1);
+        // both alleles are indels
+        let (counts, sample_indels) = indel_counts(&[vcf_line(10, "C", "CA,CAAA", ["1/2", "0/0"])]);
+        assert_eq!(counts, [(1, 1), (0, 0)]);
+        assert_eq!(sample_indels["S1"], 2);
+    }
+
+    #[test]
+    fn test_add_diversity() {
+        let (annotations, fasta_records) = test_data("add_diversity");
+        let uid = *annotations.keys().next().unwrap();
+        let annotation = &annotations[&uid];
+        let cds = CodingSequence::new(annotation, &fasta_records["contig_1"].seq);
+        // TGG > TGC and TGT, both Cys
+        let effects: Vec<SnpEffect> = [b'C', b'T']
+            .iter()
+            .enumerate()
+            .map(|(index, alt)| SnpEffect {
+                annotation,
+                allele: index + 1,
+                alt: *alt,
+                is_syn: false,
+                codon_change: cds.codon_change(9, *alt).ok(),
+            })
+            .collect();
+        let record = VcfLine::from_line(
+            "contig_1\t9\t.\tG\tC,T\t50\t.\tDP=30\tGT:AD\t0/1/2:10,6,4\t0/1:.\t1/2:0,6,4"
+                .to_string(),
+        )
+        .unwrap();
+        let sample_ids = ["S1", "S2", "S3"].map(|s| Some(s.to_string()));
+        let (pnps_map, mut extra_map) = sample_maps(uid, &["S1", "S2", "S3"]);
+        for extra in extra_map.values_mut().flat_map(|m| m.values_mut()) {
+            extra.sfs_syn = vec![0; 4];
+            extra.sfs_nonsyn = vec![0; 4];
+        }
+        let missing_depths = add_diversity(
+            &record,
+            &effects,
+            &sample_ids,
+            &pnps_map,
+            &mut extra_map,
+            Some(4),
+        );
+        assert_eq!(missing_depths, 1);
+
+        // frequencies 0.5, 0.3 and 0.2, corrected by 20 / 19
+        let extra = &extra_map["S1"][&uid];
+        let correction = 20. / 19.;
+        assert!((extra.pi - correction * 0.62).abs() < 1e-12);
+        assert!((extra.pi_nonsyn - correction * 0.5).abs() < 1e-12);
+        assert!((extra.pi_syn - correction * 0.12).abs() < 1e-12);
+        assert_eq!(extra.segregating_sites, 1);
+        // minor allele at 0.3, reference and nonsynonymous allele
+        assert_eq!(extra.sfs_syn, [0, 0, 0, 0]);
+        assert_eq!(extra.sfs_nonsyn, [0, 0, 1, 0]);
+
+        let extra = &extra_map["S2"][&uid];
+        assert_eq!((extra.pi, extra.segregating_sites), (0., 0));
+
+        // only the two alternative alleles, same amino acid
+        let extra = &extra_map["S3"][&uid];
+        assert!((extra.pi_syn - 10. / 9. * 0.48).abs() < 1e-12);
+        assert_eq!(extra.pi_nonsyn, 0.);
+        assert_eq!(extra.sfs_syn, [0, 0, 0, 1]);
+        assert_eq!(extra.sfs_nonsyn, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_count_codon_snps() {
+        let (annotations, fasta_records) = test_data("count_codon_snps");
+        let uid = *annotations.keys().next().unwrap();
+        let (mut pnps_map, mut extra_map) = sample_maps(uid, &["S1", "S2", "S3"]);
+        let multiple_snps = count_codon_snps(
+            codon_snps(uid),
+            &annotations,
+            &fasta_records,
+            &mut pnps_map,
+            &mut extra_map,
+            true,
+        );
+        assert_eq!(multiple_snps, 3);
+
+        let pnps = &pnps_map["S1"][&uid];
+        assert_eq!((pnps.syn, pnps.nonsyn), (1, 4));
+        let extra = &extra_map["S1"][&uid];
+        assert_eq!(extra.fractional_syn, Some(1.));
+        assert_eq!(extra.fractional_nonsyn, Some(4.));
+        assert_eq!((extra.stop_gain, extra.stop_loss), (1, 0));
+
+        // each SNP is counted on its own
+        let pnps = &pnps_map["S2"][&uid];
+        assert_eq!((pnps.syn, pnps.nonsyn), (0, 2));
+        assert_eq!(extra_map["S2"][&uid].stop_gain, 1);
+
+        // the fractional counts are rounded in `PnPs`
+        let pnps = &pnps_map["S3"][&uid];
+        assert_eq!((pnps.syn, pnps.nonsyn), (1, 2));
+        let extra = &extra_map["S3"][&uid];
+        assert_eq!(extra.fractional_syn, Some(0.5));
+        assert_eq!(extra.fractional_nonsyn, Some(1.5));
+    }
+
+    #[test]
+    fn test_count_codon_snps_whole_codon() {
+        let (annotations, fasta_records) = test_data("count_codon_snps_whole_codon");
+        let uid = *annotations.keys().next().unwrap();
+        let (mut pnps_map, mut extra_map) = sample_maps(uid, &["S1", "S2", "S3"]);
+        count_codon_snps(
+            codon_snps(uid),
+            &annotations,
+            &fasta_records,
+            &mut pnps_map,
+            &mut extra_map,
+            false,
+        );
+        // CTT > TTA is synonymous
+        let pnps = &pnps_map["S1"][&uid];
+        assert_eq!((pnps.syn, pnps.nonsyn), (2, 3));
+        let extra = &extra_map["S1"][&uid];
+        assert_eq!(extra.fractional_syn, None);
+        assert_eq!(extra.stop_gain, 1);
+        let pnps = &pnps_map["S3"][&uid];
+        assert_eq!((pnps.syn, pnps.nonsyn), (0, 2));
+    }
+}
@@ -0,0 +1,108 @@
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+/// An input file and its checksum
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InputFile {
+    /// What the file was used for, e.g. `vcf_file`
+    pub role: String,
+    pub path: String,
+    pub sha256: String,
+}
+
+/// Records how a file was produced: the version of the tool, when it was run,
+/// the parameters and the input files
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Provenance {
+    /// The command that produced the file, e.g. `parse`
+    pub command: String,
+    pub version: String,
+    /// RFC 3339 timestamp
+    pub timestamp: String,
+    pub command_line: Vec<String>,
+    pub parameters: BTreeMap<String, String>,
+    pub input_files: Vec<InputFile>,
+}
+
+impl Provenance {
+    /// Records the current command line, version of pnps-utils and time
+    pub fn new(command: &str) -> Self {
+        let timestamp = OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default();
+        Self {
+            command: command.to_string(),
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            timestamp,
+            command_line: std::env::args().collect(),
+            parameters: BTreeMap::new(),
+            input_files: vec![],
+        }
+    }
+
+    pub fn add_parameter<T: ToString>(&mut self, name: &str, value: T) {
+        self.parameters.insert(name.to_string(), value.to_string());
+    }
+
+    /// Adds the file, calculating its checksum
+    pub fn add_input_file<P: AsRef<Path>>(&mut self, role: &str, file_name: P) -> Result<()> {
+        self.input_files.push(InputFile {
+            role: role.to_string(),
+            path: file_name.as_ref().display().to_string(),
+            sha256: sha256_file(&file_name)?,
+        });
+        Ok(())
+    }
+
+    /// Returns the information as lines to be used as comments
+    pub fn to_comment_lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!(
+                "# pnps-utils {} {} ({})",
+                self.command, self.version, self.timestamp
+            ),
+            format!("# command line: {}", self.command_line.join(" ")),
+        ];
+        for (name, value) in self.parameters.iter() {
+            lines.push(format!("# parameter {}: {}", name, value));
+        }
+        for input_file in self.input_files.iter() {
+            lines.push(format!(
+                "# input {}: {} (sha256: {})",
+                input_file.role, input_file.path, input_file.sha256
+            ));
+        }
+        lines
+    }
+}
+
+/// Returns the SHA-256 of the file, as a hex string
+pub fn sha256_file<P: AsRef<Path>>(file_name: P) -> Result<String> {
+    info!("Calculating checksum of {}", file_name.as_ref().display());
+    let mut reader = BufReader::new(
+        File::open(&file_name)
+            .with_context(|| format!("Cannot open file {}", file_name.as_ref().display()))?,
+    );
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 1 << 16];
+    loop {
+        let size = reader.read(&mut buffer)?;
+        if size == 0 {
+            break;
+        }
+        hasher.update(&buffer[..size]);
+    }
+    Ok(hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect())
+}
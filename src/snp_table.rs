@@ -0,0 +1,63 @@
+//! Table with the details of each SNP counted by `parse`, to check single
+//! genes and find recurrent mutations
+use super::utils::{create_output_file, OutputFile};
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::path::Path;
+use uuid::Uuid;
+
+/// One SNP in a CDS, for one sample. The codon fields are empty if the codon
+/// cannot be determined (e.g. incomplete codon at the end of the CDS).
+#[derive(Debug, Serialize)]
+pub struct SnpRow<'a> {
+    pub sample: &'a str,
+    pub contig: &'a str,
+    pub position: usize,
+    #[serde(rename = "ref")]
+    pub ref_allele: &'a str,
+    pub alt: &'a str,
+    pub uid: Uuid,
+    pub locus_tag: Option<&'a str>,
+    pub codon_number: Option<usize>,
+    pub codon_position: Option<usize>,
+    pub ref_codon: Option<String>,
+    pub alt_codon: Option<String>,
+    pub ref_aa: Option<char>,
+    pub alt_aa: Option<char>,
+    /// `syn`, `nonsyn`, `stop_gain` or `stop_loss`, the last two are also
+    /// counted as nonsynonymous
+    pub class: &'static str,
+    /// Depth of the sample at the position
+    pub depth: Option<u32>,
+    /// Frequency of the alternative allele in the sample
+    pub allele_frequency: Option<f64>,
+}
+
+/// Writes the SNP table as tab separated values, compressed if the file name
+/// ends in `.gz` or `.zst`
+pub struct SnpTableWriter {
+    writer: csv::Writer<OutputFile>,
+}
+
+impl SnpTableWriter {
+    pub fn new<P: AsRef<Path>>(file_name: P) -> Result<Self> {
+        let writer = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(create_output_file(file_name)?);
+        Ok(Self { writer })
+    }
+
+    pub fn write(&mut self, row: &SnpRow) -> Result<()> {
+        self.writer
+            .serialize(row)
+            .context("Problem writing SNP table")
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("Problem flushing SNP table to disk")?
+            .finish()
+    }
+}
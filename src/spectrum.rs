@@ -0,0 +1,183 @@
+//! Mutation spectrum of the SNPs in each sample, with the substitutions
+//! collapsed to the pyrimidine of the reference base, to spot library damage or
+//! problems in the variant calling
+use super::codon::complement;
+use super::utils::create_output_file;
+use anyhow::{Context, Result};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Substitutions from the pyrimidines, the others are collapsed onto them
+static SUBSTITUTIONS: [(u8, &[u8; 3]); 2] = [(b'C', b"AGT"), (b'T', b"ACG")];
+static BASES: &[u8; 4] = b"ACGT";
+
+/// Counts of each substitution class, by sample and region (coding or not)
+pub struct MutationSpectrum {
+    /// Uses the trinucleotide context, 96 classes instead of 6
+    context: bool,
+    classes: Vec<String>,
+    counts: BTreeMap<(String, bool), Vec<u32>>,
+}
+
+impl MutationSpectrum {
+    pub fn new(context: bool) -> Self {
+        let mut classes = vec![];
+        for (ref_base, alt_bases) in SUBSTITUTIONS.iter() {
+            for alt_base in alt_bases.iter() {
+                let substitution = format!("{}>{}", *ref_base as char, *alt_base as char);
+                if !context {
+                    classes.push(substitution);
+                    continue;
+                }
+                for five_prime in BASES.iter() {
+                    for three_prime in BASES.iter() {
+                        classes.push(format!(
+                            "{}[{}]{}",
+                            *five_prime as char, substitution, *three_prime as char
+                        ));
+                    }
+                }
+            }
+        }
+        Self {
+            context,
+            classes,
+            counts: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the index of the class, the flanking bases (5' and 3') are only
+    /// used with the context. `None` if a base is not `ACGT` or the bases are
+    /// the same.
+    fn class_index(&self, ref_base: u8, alt_base: u8, flanks: Option<(u8, u8)>) -> Option<usize> {
+        let mut ref_base = ref_base.to_ascii_uppercase();
+        let mut alt_base = alt_base.to_ascii_uppercase();
+        let mut flanks = flanks.map(|(a, b)| (a.to_ascii_uppercase(), b.to_ascii_uppercase()));
+        if ref_base == b'A' || ref_base == b'G' {
+            ref_base = complement(ref_base);
+            alt_base = complement(alt_base);
+            // the reverse strand is read in the opposite direction
+            flanks = flanks.map(|(a, b)| (complement(b), complement(a)));
+        }
+        let (substitution, (_, alt_bases)) = SUBSTITUTIONS
+            .iter()
+            .enumerate()
+            .find(|(_, (base, _))| *base == ref_base)?;
+        let index = substitution * 3 + alt_bases.iter().position(|b| *b == alt_base)?;
+        if !self.context {
+            return Some(index);
+        }
+        let (five_prime, three_prime) = flanks?;
+        let five_prime = BASES.iter().position(|b| *b == five_prime)?;
+        let three_prime = BASES.iter().position(|b| *b == three_prime)?;
+        Some(index * 16 + five_prime * 4 + three_prime)
+    }
+
+    /// Adds a SNP, returns false if it cannot be classified, e.g. other
+    /// bases or missing context
+    pub fn add(
+        &mut self,
+        sample_id: &str,
+        coding: bool,
+        ref_base: u8,
+        alt_base: u8,
+        flanks: Option<(u8, u8)>,
+    ) -> bool {
+        let index = match self.class_index(ref_base, alt_base, flanks) {
+            None => return false,
+            Some(value) => value,
+        };
+        let n_classes = self.classes.len();
+        self.counts
+            .entry((sample_id.to_string(), coding))
+            .or_insert_with(|| vec![0; n_classes])[index] += 1;
+        true
+    }
+
+    /// Writes a tab separated table, with one row for each sample and region
+    /// and one column for each class. The file is compressed if the name ends
+    /// in `.gz` or `.zst`.
+    pub fn write<P: AsRef<Path>>(&self, file_name: P) -> Result<()> {
+        let mut writer = csv::WriterBuilder::new()
+            .delimiter(b'\t')
+            .from_writer(create_output_file(file_name)?);
+        let mut record: Vec<String> = vec!["sample".to_string(), "region".to_string()];
+        record.extend(self.classes.iter().cloned());
+        writer
+            .write_record(&record)
+            .context("Problem writing mutation spectrum")?;
+        for ((sample_id, coding), counts) in self.counts.iter() {
+            record.clear();
+            record.push(sample_id.clone());
+            record.push(match coding {
+                true => "coding".to_string(),
+                false => "noncoding".to_string(),
+            });
+            record.extend(counts.iter().map(|c| c.to_string()));
+            writer
+                .write_record(&record)
+                .context("Problem writing mutation spectrum")?;
+        }
+        writer
+            .into_inner()
+            .map_err(|err| err.into_error())
+            .context("Problem flushing mutation spectrum to disk")?
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_classes() {
+        let spectrum = MutationSpectrum::new(false);
+        assert_eq!(spectrum.classes, ["C>A", "C>G", "C>T", "T>A", "T>C", "T>G"]);
+        let spectrum = MutationSpectrum::new(true);
+        assert_eq!(spectrum.classes.len(), 96);
+        assert_eq!(spectrum.classes[0], "A[C>A]A");
+        assert_eq!(spectrum.classes[95], "T[T>G]T");
+    }
+
+    #[test]
+    fn test_class_index() {
+        let spectrum = MutationSpectrum::new(false);
+        assert_eq!(spectrum.class_index(b'C', b'T', None), Some(2));
+        // collapsed on the pyrimidine
+        assert_eq!(spectrum.class_index(b'G', b'A', None), Some(2));
+        assert_eq!(spectrum.class_index(b'a', b'g', None), Some(4));
+        assert_eq!(spectrum.class_index(b'C', b'C', None), None);
+        assert_eq!(spectrum.class_index(b'N', b'C', None), None);
+
+        let spectrum = MutationSpectrum::new(true);
+        let index = spectrum
+            .class_index(b'C', b'T', Some((b'A', b'G')))
+            .unwrap();
+        assert_eq!(spectrum.classes[index], "A[C>T]G");
+        // the flanks are swapped on the reverse strand
+        let index = spectrum
+            .class_index(b'G', b'A', Some((b'A', b'C')))
+            .unwrap();
+        assert_eq!(spectrum.classes[index], "G[C>T]T");
+        assert_eq!(spectrum.class_index(b'C', b'T', None), None);
+        assert_eq!(spectrum.class_index(b'C', b'T', Some((b'N', b'A'))), None);
+    }
+
+    #[test]
+    fn test_add() {
+        let mut spectrum = MutationSpectrum::new(false);
+        assert!(spectrum.add("S1", true, b'C', b'T', None));
+        assert!(spectrum.add("S1", true, b'G', b'A', None));
+        assert!(spectrum.add("S1", false, b'T', b'G', None));
+        assert!(!spectrum.add("S1", false, b'T', b'N', None));
+        assert_eq!(
+            spectrum.counts[&("S1".to_string(), true)],
+            [0, 0, 2, 0, 0, 0]
+        );
+        assert_eq!(
+            spectrum.counts[&("S1".to_string(), false)],
+            [0, 0, 0, 0, 0, 1]
+        );
+    }
+}
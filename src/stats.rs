@@ -0,0 +1,365 @@
+//! Statistical functions used by `compare`, `calc` and `inspect`
+
+/// Returns the median of the values, the slice is sorted in place
+pub fn median(values: &mut [f64]) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let middle = values.len() / 2;
+    if values.len() % 2 == 1 {
+        values[middle]
+    } else {
+        (values[middle - 1] + values[middle]) / 2.
+    }
+}
+
+/// Linear interpolation between the closest ranks, `values` must be sorted.
+/// There is no interpolation with infinite values, the lower rank is used.
+pub fn quantile(values: &[f64], q: f64) -> f64 {
+    if values.is_empty() {
+        return f64::NAN;
+    }
+    let position = q * (values.len() - 1) as f64;
+    let lower = position.floor() as usize;
+    let upper = position.ceil() as usize;
+    if values[lower] == values[upper] || values[lower].is_infinite() || values[upper].is_infinite()
+    {
+        // `inf - inf` is `NaN`
+        return values[lower];
+    }
+    values[lower] + (values[upper] - values[lower]) * (position - lower as f64)
+}
+
+/// Natural logarithm of the Gamma function, Lanczos approximation (g = 7)
+pub fn ln_gamma(x: f64) -> f64 {
+    const COEFFICIENTS: [f64; 9] = [
+        0.999_999_999_999_809_9,
+        676.520_368_121_885_1,
+        -1_259.139_216_722_402_8,
+        771.323_428_777_653_1,
+        -176.615_029_162_140_6,
+        12.507_343_278_686_905,
+        -0.138_571_095_265_720_12,
+        9.984_369_578_019_572e-6,
+        1.505_632_735_149_311_6e-7,
+    ];
+    if x < 0.5 {
+        // reflection formula
+        std::f64::consts::PI.ln() - (std::f64::consts::PI * x).sin().abs().ln() - ln_gamma(1. - x)
+    } else {
+        let x = x - 1.;
+        let t = x + 7.5;
+        let series = COEFFICIENTS
+            .iter()
+            .enumerate()
+            .skip(1)
+            .fold(COEFFICIENTS[0], |acc, (i, c)| acc + c / (x + i as f64));
+        0.5 * (2. * std::f64::consts::PI).ln() + (x + 0.5) * t.ln() - t + series.ln()
+    }
+}
+
+/// Natural logarithm of n!
+pub fn ln_factorial(n: u64) -> f64 {
+    ln_gamma(n as f64 + 1.)
+}
+
+/// Complementary error function, with fractional error below 1.2e-7
+/// (Numerical Recipes `erfcc`)
+pub fn erfc(x: f64) -> f64 {
+    let z = x.abs();
+    let t = 1. / (1. + 0.5 * z);
+    let r = t
+        * (-z * z - 1.265_512_23
+            + t * (1.000_023_68
+                + t * (0.374_091_96
+                    + t * (0.096_784_18
+                        + t * (-0.186_288_06
+                            + t * (0.278_868_07
+                                + t * (-1.135_203_98
+                                    + t * (1.488_515_87
+                                        + t * (-0.822_152_23 + t * 0.170_872_77)))))))))
+            .exp();
+    if x >= 0. {
+        r
+    } else {
+        2. - r
+    }
+}
+
+/// Cumulative distribution function of the standard normal distribution
+pub fn normal_cdf(x: f64) -> f64 {
+    0.5 * erfc(-x / std::f64::consts::SQRT_2)
+}
+
+/// Result of the Wilcoxon rank-sum (Mann-Whitney U) test
+#[derive(Debug, Clone, Copy)]
+pub struct RankSumResult {
+    /// U statistic for the first sample
+    pub u: f64,
+    /// Two-sided p-value
+    pub p_value: f64,
+    /// Rank-biserial correlation, positive if the values in the second sample
+    /// tend to be larger
+    pub rank_biserial: f64,
+}
+
+/// Wilcoxon rank-sum test, using the normal approximation with tie and
+/// continuity corrections. Returns `None` if any of the samples is empty.
+pub fn wilcoxon_rank_sum(a: &[f64], b: &[f64]) -> Option<RankSumResult> {
+    if a.is_empty() || b.is_empty() {
+        return None;
+    }
+    let n1 = a.len() as f64;
+    let n2 = b.len() as f64;
+    let mut values: Vec<(f64, bool)> = a
+        .iter()
+        .map(|v| (*v, true))
+        .chain(b.iter().map(|v| (*v, false)))
+        .collect();
+    values.sort_by(|x, y| x.0.total_cmp(&y.0));
+
+    // average ranks for ties
+    let mut rank_sum_a = 0.;
+    let mut tie_correction = 0.;
+    let mut start = 0;
+    while start < values.len() {
+        let mut end = start;
+        while end + 1 < values.len() && values[end + 1].0 == values[start].0 {
+            end += 1;
+        }
+        let rank = (start + end) as f64 / 2. + 1.;
+        let ties = (end - start + 1) as f64;
+        tie_correction += ties.powi(3) - ties;
+        rank_sum_a += rank * values[start..=end].iter().filter(|v| v.1).count() as f64;
+        start = end + 1;
+    }
+
+    let u = rank_sum_a - n1 * (n1 + 1.) / 2.;
+    let mean = n1 * n2 / 2.;
+    let n = n1 + n2;
+    let variance = n1 * n2 / 12. * ((n + 1.) - tie_correction / (n * (n - 1.)));
+    let p_value = if variance <= 0. {
+        1.
+    } else {
+        let z = ((u - mean).abs() - 0.5).max(0.) / variance.sqrt();
+        (2. * (1. - normal_cdf(z))).min(1.)
+    };
+
+    Some(RankSumResult {
+        u,
+        p_value,
+        rank_biserial: 1. - 2. * u / (n1 * n2),
+    })
+}
+
+/// Two-sided exact binomial test of `k` successes in `n` trials, with
+/// probability of success `p`. The p-value is the sum of the probabilities of
+/// the outcomes that are not more likely than the observed one.
+pub fn binomial_test(k: u64, n: u64, p: f64) -> f64 {
+    if !(0. ..=1.).contains(&p) || n == 0 {
+        return f64::NAN;
+    }
+    let ln_pmf = |x: u64| -> f64 {
+        let successes = match x {
+            0 => 0.,
+            x => x as f64 * p.ln(),
+        };
+        let failures = match n - x {
+            0 => 0.,
+            y => y as f64 * (1. - p).ln(),
+        };
+        ln_factorial(n) - ln_factorial(x) - ln_factorial(n - x) + successes + failures
+    };
+    let observed = ln_pmf(k);
+    let p_value: f64 = (0..=n)
+        .map(ln_pmf)
+        // relative tolerance, as in R
+        .filter(|value| *value <= observed + 1e-7)
+        .map(|value| value.exp())
+        .sum();
+    p_value.min(1.)
+}
+
+/// Log probability of a 2x2 table with the given margins (hypergeometric)
+fn ln_hypergeometric(a: u64, b: u64, c: u64, d: u64) -> f64 {
+    ln_factorial(a + b) + ln_factorial(c + d) + ln_factorial(a + c) + ln_factorial(b + d)
+        - ln_factorial(a)
+        - ln_factorial(b)
+        - ln_factorial(c)
+        - ln_factorial(d)
+        - ln_factorial(a + b + c + d)
+}
+
+/// Two-sided Fisher exact test for the 2x2 table `[[a, b], [c, d]]`. The
+/// p-value is the sum of the probabilities of the tables, with the same
+/// margins, that are not more likely than the observed one.
+pub fn fisher_exact(a: u64, b: u64, c: u64, d: u64) -> f64 {
+    let row1 = a + b;
+    let col1 = a + c;
+    let total = a + b + c + d;
+    let observed = ln_hypergeometric(a, b, c, d);
+
+    let min_a = col1.saturating_sub(total - row1);
+    let max_a = row1.min(col1);
+    let mut p_value = 0.;
+    for x in min_a..=max_a {
+        let p = ln_hypergeometric(x, row1 - x, col1 - x, total + x - row1 - col1);
+        // relative tolerance, as in R
+        if p <= observed + 1e-7 {
+            p_value += p.exp();
+        }
+    }
+    p_value.min(1.)
+}
+
+/// Benjamini-Hochberg adjusted p-values (q-values), `NaN` values are kept as
+/// they are and not counted in the number of tests
+pub fn benjamini_hochberg(p_values: &[f64]) -> Vec<f64> {
+    let mut order: Vec<usize> = (0..p_values.len())
+        .filter(|i| !p_values[*i].is_nan())
+        .collect();
+    order.sort_by(|i, j| p_values[*j].total_cmp(&p_values[*i]));
+
+    let n = order.len() as f64;
+    let mut q_values = vec![f64::NAN; p_values.len()];
+    let mut current_min = 1f64;
+    for (position, index) in order.iter().enumerate() {
+        let rank = n - position as f64;
+        current_min = current_min.min(p_values[*index] * n / rank);
+        q_values[*index] = current_min;
+    }
+    q_values
+}
+
+/// Harmonic sums `a1 = Σ 1/i` and `a2 = Σ 1/i²`, for i from 1 to n - 1
+fn harmonic_sums(n: u64) -> (f64, f64) {
+    (1..n).fold((0., 0.), |(a1, a2), i| {
+        let i = i as f64;
+        (a1 + 1. / i, a2 + 1. / (i * i))
+    })
+}
+
+/// Watterson's θ for `segregating` sites in a sample of `n` sequences, not
+/// divided by the number of sites. `NaN` if `n` is less than 2.
+pub fn watterson_theta(segregating: f64, n: u64) -> f64 {
+    if n < 2 {
+        return f64::NAN;
+    }
+    segregating / harmonic_sums(n).0
+}
+
+/// Tajima's D from the mean pairwise differences `pi`, the `segregating`
+/// sites and the sample size `n`. `NaN` without segregating sites or if `n` is
+/// less than 4, where the variance cannot be estimated.
+pub fn tajima_d(pi: f64, segregating: f64, n: u64) -> f64 {
+    if n < 4 || segregating <= 0. {
+        return f64::NAN;
+    }
+    let (a1, a2) = harmonic_sums(n);
+    let n = n as f64;
+    let b1 = (n + 1.) / (3. * (n - 1.));
+    let b2 = 2. * (n * n + n + 3.) / (9. * n * (n - 1.));
+    let c1 = b1 - 1. / a1;
+    let c2 = b2 - (n + 2.) / (a1 * n) + a2 / (a1 * a1);
+    let e1 = c1 / a1;
+    let e2 = c2 / (a1 * a1 + a2);
+    (pi - segregating / a1) / (e1 * segregating + e2 * segregating * (segregating - 1.)).sqrt()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(value: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (value - expected).abs() < tolerance,
+            "{} is not {}",
+            value,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_median_quantile() {
+        assert_eq!(median(&mut [3., 1., 2.]), 2.);
+        assert_eq!(median(&mut [4., 1., 3., 2.]), 2.5);
+        assert!(median(&mut []).is_nan());
+        let values = [1., 2., 3., 4., 5.];
+        assert_close(quantile(&values, 0.25), 2., 1e-12);
+        assert_close(quantile(&values, 0.1), 1.4, 1e-12);
+        assert_close(quantile(&values, 1.), 5., 1e-12);
+        // bootstrap replicates without synonymous SNPs
+        let values = [1., 2., f64::INFINITY, f64::INFINITY];
+        assert_eq!(quantile(&values, 0.9), f64::INFINITY);
+        assert_eq!(quantile(&values, 0.5), 2.);
+        assert_eq!(quantile(&[f64::INFINITY; 3], 0.975), f64::INFINITY);
+    }
+
+    #[test]
+    fn test_ln_gamma() {
+        assert_close(ln_gamma(5.), 24f64.ln(), 1e-10);
+        assert_close(ln_gamma(0.5), std::f64::consts::PI.sqrt().ln(), 1e-10);
+        assert_close(ln_factorial(10), 3_628_800f64.ln(), 1e-10);
+        assert_close(ln_factorial(0), 0., 1e-10);
+    }
+
+    #[test]
+    fn test_normal_cdf() {
+        assert_close(normal_cdf(0.), 0.5, 1e-7);
+        assert_close(normal_cdf(1.96), 0.975, 1e-4);
+        assert_close(normal_cdf(-1.96), 0.025, 1e-4);
+    }
+
+    #[test]
+    fn test_wilcoxon_rank_sum() {
+        // R: wilcox.test(c(1, 2, 3), c(4, 5, 6), exact = FALSE)
+        let result = wilcoxon_rank_sum(&[1., 2., 3.], &[4., 5., 6.]).unwrap();
+        assert_eq!(result.u, 0.);
+        assert_eq!(result.rank_biserial, 1.);
+        assert_close(result.p_value, 0.080856, 1e-5);
+        assert!(wilcoxon_rank_sum(&[], &[1.]).is_none());
+    }
+
+    #[test]
+    fn test_binomial_test() {
+        // R: binom.test(7, 10, 0.5)
+        assert_close(binomial_test(7, 10, 0.5), 0.34375, 1e-10);
+        // R: binom.test(0, 10, 0.5)
+        assert_close(binomial_test(0, 10, 0.5), 2. / 1024., 1e-10);
+        assert_close(binomial_test(5, 10, 0.5), 1., 1e-10);
+        assert!(binomial_test(1, 0, 0.5).is_nan());
+    }
+
+    #[test]
+    fn test_fisher_exact() {
+        // R: fisher.test(matrix(c(3, 1, 1, 3), nrow = 2)), tea tasting
+        assert_close(fisher_exact(3, 1, 1, 3), 34. / 70., 1e-10);
+        assert_close(fisher_exact(2, 2, 2, 2), 1., 1e-10);
+    }
+
+    #[test]
+    fn test_benjamini_hochberg() {
+        // R: p.adjust(c(0.01, 0.04, 0.03, 0.005), "BH")
+        let q_values = benjamini_hochberg(&[0.01, 0.04, 0.03, 0.005]);
+        for (value, expected) in q_values.iter().zip([0.02, 0.04, 0.04, 0.02]) {
+            assert_close(*value, expected, 1e-12);
+        }
+        let q_values = benjamini_hochberg(&[f64::NAN, 0.01, 0.04]);
+        assert!(q_values[0].is_nan());
+        assert_close(q_values[1], 0.02, 1e-12);
+        assert_close(q_values[2], 0.04, 1e-12);
+    }
+
+    #[test]
+    fn test_watterson_tajima() {
+        // a1 = 1 + 1/2 + 1/3 for 4 sequences
+        assert_close(watterson_theta(11., 4), 6., 1e-12);
+        assert!(watterson_theta(1., 1).is_nan());
+        // pi equal to Watterson's θ
+        let a1 = harmonic_sums(10).0;
+        assert_close(tajima_d(5. / a1, 5., 10), 0., 1e-12);
+        assert!(tajima_d(1., 5., 3).is_nan());
+        assert!(tajima_d(0., 0., 10).is_nan());
+    }
+}
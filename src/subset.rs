@@ -0,0 +1,108 @@
+use super::cli::{MapKey, Subset};
+use super::data::{read_parse_output, save_parse_output, KeyResolver};
+use super::provenance::Provenance;
+use super::utils::read_list_file;
+use anyhow::{bail, Result};
+use log::{info, warn};
+use std::collections::HashSet;
+use std::path::Path;
+use uuid::Uuid;
+
+/// Reads a list of keys and resolves them to UIDs
+fn read_uid_list<P: AsRef<Path>>(file_name: P, resolver: &KeyResolver) -> Result<HashSet<Uuid>> {
+    let mut uids = HashSet::new();
+    let mut not_found = 0u32;
+    for key in read_list_file(file_name)? {
+        let resolved = resolver.resolve(&key)?;
+        if resolved.is_empty() {
+            not_found += 1;
+        }
+        uids.extend(resolved);
+    }
+    if not_found > 0 {
+        warn!("Keys not found in the annotations: {}", not_found);
+    }
+    Ok(uids)
+}
+
+pub fn subset_command(options: Subset) -> Result<()> {
+    let mut provenance = Provenance::new("subset");
+    provenance.add_parameter("gene_key", options.gene_key);
+    provenance.add_input_file("input_file", &options.input_file)?;
+
+    let mut parse_output = read_parse_output(&options.input_file)?;
+
+    let mut keep_samples: HashSet<String> = parse_output.samples.keys().cloned().collect();
+    if let Some(file_name) = &options.samples {
+        provenance.add_input_file("samples", file_name)?;
+        let samples = read_list_file(file_name)?;
+        for sample_id in samples.difference(&keep_samples) {
+            warn!("Sample {} not found in the input file", sample_id);
+        }
+        keep_samples.retain(|s| samples.contains(s));
+    }
+    if let Some(file_name) = &options.exclude_samples {
+        provenance.add_input_file("exclude_samples", file_name)?;
+        let samples = read_list_file(file_name)?;
+        keep_samples.retain(|s| !samples.contains(s));
+    }
+
+    let mut keep_uids: HashSet<Uuid> = parse_output.annotations.keys().cloned().collect();
+    let gene_resolver = KeyResolver::new(options.gene_key, &parse_output.annotations);
+    let contig_resolver = KeyResolver::new(MapKey::Contig, &parse_output.annotations);
+    if let Some(file_name) = &options.genes {
+        provenance.add_input_file("genes", file_name)?;
+        let uids = read_uid_list(file_name, &gene_resolver)?;
+        keep_uids.retain(|uid| uids.contains(uid));
+    }
+    if let Some(file_name) = &options.contigs {
+        provenance.add_input_file("contigs", file_name)?;
+        let uids = read_uid_list(file_name, &contig_resolver)?;
+        keep_uids.retain(|uid| uids.contains(uid));
+    }
+    if let Some(file_name) = &options.exclude_genes {
+        provenance.add_input_file("exclude_genes", file_name)?;
+        let uids = read_uid_list(file_name, &gene_resolver)?;
+        keep_uids.retain(|uid| !uids.contains(uid));
+    }
+    if let Some(file_name) = &options.exclude_contigs {
+        provenance.add_input_file("exclude_contigs", file_name)?;
+        let uids = read_uid_list(file_name, &contig_resolver)?;
+        keep_uids.retain(|uid| !uids.contains(uid));
+    }
+
+    if keep_samples.is_empty() {
+        bail!("No samples left after filtering");
+    }
+    if keep_uids.is_empty() {
+        bail!("No annotations left after filtering");
+    }
+    info!(
+        "Keeping {} of {} samples and {} of {} annotations",
+        keep_samples.len(),
+        parse_output.samples.len(),
+        keep_uids.len(),
+        parse_output.annotations.len()
+    );
+
+    parse_output
+        .samples
+        .retain(|sample_id, _| keep_samples.contains(sample_id));
+    for sample_map in parse_output.samples.values_mut() {
+        sample_map.retain(|uid, _| keep_uids.contains(uid));
+    }
+    parse_output
+        .extra
+        .retain(|sample_id, _| keep_samples.contains(sample_id));
+    for sample_map in parse_output.extra.values_mut() {
+        sample_map.retain(|uid, _| keep_uids.contains(uid));
+    }
+    parse_output
+        .annotations
+        .retain(|uid, _| keep_uids.contains(uid));
+
+    parse_output.provenance.push(provenance);
+    save_parse_output(&options.output_file, parse_output)?;
+
+    Ok(())
+}
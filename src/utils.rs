@@ -1,7 +1,10 @@
-use anyhow::{bail, Result};
+use anyhow::{bail, Context, Result};
+use flate2::write::GzEncoder;
+use flate2::Compression;
 use log::{error, info};
+use std::collections::HashSet;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
 use std::path::Path;
 
 /// Checks passed Option<Path> and returns an open file, if the Option is
@@ -38,3 +41,100 @@ pub fn file_or_stdin<P: AsRef<Path>>(input_file: &Option<P>) -> Result<Box<dyn R
     };
     Ok(result)
 }
+
+/// File created by `create_output_file`. `finish` must be called at the end,
+/// as the compressed formats write their trailer when finished and the errors
+/// are lost if they are dropped instead.
+pub enum OutputFile {
+    Plain(BufWriter<File>),
+    Gzip(GzEncoder<BufWriter<File>>),
+    Zstd(zstd::Encoder<'static, BufWriter<File>>),
+}
+
+impl OutputFile {
+    /// Ends the compressed stream, if any, and flushes the file
+    pub fn finish(self) -> Result<()> {
+        let mut handle = match self {
+            Self::Plain(handle) => handle,
+            Self::Gzip(encoder) => encoder.finish().context("Cannot finish gzip stream")?,
+            Self::Zstd(encoder) => encoder.finish().context("Cannot finish zstd stream")?,
+        };
+        handle.flush().context("Problem flushing file to disk")
+    }
+}
+
+impl Write for OutputFile {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Self::Plain(handle) => handle.write(buf),
+            Self::Gzip(encoder) => encoder.write(buf),
+            Self::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Self::Plain(handle) => handle.flush(),
+            Self::Gzip(encoder) => encoder.flush(),
+            Self::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Creates a file for writing, the compression is chosen from the extension:
+/// `.gz` for gzip, `.zst` for zstd and no compression otherwise.
+pub fn create_output_file<P: AsRef<Path>>(file_name: P) -> Result<OutputFile> {
+    let file_name = file_name.as_ref();
+    let handle = BufWriter::new(
+        File::create(file_name)
+            .with_context(|| format!("Cannot create file {}", file_name.display()))?,
+    );
+    let result = match file_name.extension().and_then(|e| e.to_str()) {
+        Some("gz") => {
+            info!("Writing gzip compressed file: {}", file_name.display());
+            OutputFile::Gzip(GzEncoder::new(handle, Compression::default()))
+        }
+        Some("zst") => {
+            info!("Writing zstd compressed file: {}", file_name.display());
+            OutputFile::Zstd(zstd::Encoder::new(handle, 0)?)
+        }
+        _ => {
+            info!("Writing file: {}", file_name.display());
+            OutputFile::Plain(handle)
+        }
+    };
+    Ok(result)
+}
+
+/// Opens a file for reading, `.zst` files are decompressed with zstd, other
+/// files are opened with `bio_rascal::io::open_file_base`, which supports
+/// gzip. The returned reader is buffered.
+pub fn open_input_file<P: AsRef<Path>>(file_name: P) -> Result<Box<dyn Read>> {
+    let file_name = file_name.as_ref();
+    let result = match file_name.extension().and_then(|e| e.to_str()) {
+        Some("zst") => {
+            let handle = File::open(file_name)
+                .with_context(|| format!("Cannot open file {}", file_name.display()))?;
+            Box::new(BufReader::new(zstd::Decoder::new(handle)?)) as Box<dyn Read>
+        }
+        _ => Box::new(BufReader::new(bio_rascal::io::open_file_base(file_name)?)),
+    };
+    Ok(result)
+}
+
+/// Reads a file with one element per line, empty lines and lines starting
+/// with `#` are skipped
+pub fn read_list_file<P: AsRef<Path>>(file_name: P) -> Result<HashSet<String>> {
+    info!("Reading list from file: {}", file_name.as_ref().display());
+    let file_handle = bio_rascal::io::open_file(file_name).context("Cannot open file")?;
+    let mut values = HashSet::new();
+    for line in file_handle.lines() {
+        let line = line.context("Problem parsing line")?;
+        let line = line.trim();
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+        values.insert(line.to_string());
+    }
+    Ok(values)
+}
@@ -0,0 +1,382 @@
+//! Minimal VCF reader and writer, used by `parse` because it needs the
+//! per-sample fields (`FORMAT`) and the original lines, which
+//! `bio_rascal::snps::VcfReader` does not keep
+use super::utils::{create_output_file, OutputFile};
+use anyhow::{bail, Context, Result};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// A VCF record, the sample columns are parsed on request
+#[derive(Debug, Clone)]
+pub struct VcfLine {
+    pub chrom: String,
+    /// 1-based, as in the file
+    pub pos: usize,
+    pub ref_allele: String,
+    pub alt_alleles: Vec<String>,
+    /// `NaN` if missing (`.`), check it explicitly as comparisons are false
+    pub qual: f64,
+    pub info: String,
+    format: Vec<String>,
+    samples: Vec<String>,
+    /// The line as read from the file, without the newline
+    line: String,
+}
+
+impl VcfLine {
+    /// Parses a record, the line is without the newline
+    pub fn from_line(line: String) -> Result<Self> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        if fields.len() < 8 {
+            bail!("Expected at least 8 columns in VCF, got {}", fields.len());
+        }
+        let pos = fields[1]
+            .parse::<usize>()
+            .with_context(|| format!("Cannot parse position {}", fields[1]))?;
+        let qual = match fields[5] {
+            "." => f64::NAN,
+            value => value
+                .parse::<f64>()
+                .with_context(|| format!("Cannot parse QUAL {}", value))?,
+        };
+        let alt_alleles = match fields[4] {
+            "." => vec![],
+            value => value.split(',').map(|a| a.to_string()).collect(),
+        };
+        let format = match fields.get(8) {
+            None => vec![],
+            Some(value) => value.split(':').map(|f| f.to_string()).collect(),
+        };
+        let samples = fields.iter().skip(9).map(|s| s.to_string()).collect();
+        Ok(Self {
+            chrom: fields[0].to_string(),
+            pos,
+            ref_allele: fields[3].to_string(),
+            alt_alleles,
+            qual,
+            info: fields[7].to_string(),
+            format,
+            samples,
+            line,
+        })
+    }
+
+    /// Returns the value of the `INFO` key, an empty string for flags
+    pub fn info_value(&self, key: &str) -> Option<&str> {
+        self.info.split(';').find_map(|field| {
+            let mut parts = field.splitn(2, '=');
+            match parts.next() {
+                Some(name) if name == key => Some(parts.next().unwrap_or("")),
+                _ => None,
+            }
+        })
+    }
+
+    /// Depth from the `DP` key in `INFO`, 0 if missing
+    pub fn depth(&self) -> u32 {
+        self.info_value("DP")
+            .and_then(|value| value.parse().ok())
+            .unwrap_or(0)
+    }
+
+    /// True if the record is flagged as `INDEL` or the alleles have different
+    /// lengths
+    pub fn is_indel(&self) -> bool {
+        self.info_value("INDEL").is_some()
+            || self.ref_allele.len() > 1
+            || self
+                .alt_alleles
+                .iter()
+                .any(|alt| alt.len() != self.ref_allele.len())
+    }
+
+    /// Returns the allele, 0 is the reference
+    pub fn allele(&self, index: usize) -> Option<&str> {
+        match index {
+            0 => Some(&self.ref_allele),
+            index => self.alt_alleles.get(index - 1).map(|a| a.as_str()),
+        }
+    }
+
+    /// Returns the value of a `FORMAT` key for the sample (by index)
+    pub fn sample_value(&self, sample_index: usize, key: &str) -> Option<&str> {
+        let position = self.format.iter().position(|f| f == key)?;
+        let value = self.samples.get(sample_index)?.split(':').nth(position)?;
+        match value {
+            "." | "" => None,
+            value => Some(value),
+        }
+    }
+
+    /// Alleles in the genotype (`GT`) of the sample, missing ones are skipped
+    pub fn sample_alleles(&self, sample_index: usize) -> Vec<usize> {
+        match self.sample_value(sample_index, "GT") {
+            None => vec![],
+            Some(value) => value
+                .split(['/', '|'])
+                .filter_map(|a| a.parse().ok())
+                .collect(),
+        }
+    }
+
+    /// Depth of each allele (`AD`) in the sample
+    pub fn allele_depths(&self, sample_index: usize) -> Option<Vec<u32>> {
+        self.sample_value(sample_index, "AD")?
+            .split(',')
+            .map(|d| d.parse().ok())
+            .collect()
+    }
+
+    /// Depth of the sample, from `AD` if present, otherwise from `DP`
+    pub fn sample_depth(&self, sample_index: usize) -> Option<u32> {
+        match self.allele_depths(sample_index) {
+            Some(depths) => Some(depths.iter().sum()),
+            None => self.sample_value(sample_index, "DP")?.parse().ok(),
+        }
+    }
+
+    /// Frequency of the allele in the sample, from `AD`
+    pub fn allele_frequency(&self, sample_index: usize, allele: usize) -> Option<f64> {
+        let depths = self.allele_depths(sample_index)?;
+        let total: u32 = depths.iter().sum();
+        match total {
+            0 => None,
+            total => Some(*depths.get(allele)? as f64 / total as f64),
+        }
+    }
+
+    /// Returns the line, with the entries added to the `INFO` column
+    pub fn line_with_info(&self, entries: &[String]) -> String {
+        if entries.is_empty() {
+            return self.line.clone();
+        }
+        let info = match self.info.as_str() {
+            "." => entries.join(";"),
+            info => format!("{};{}", info, entries.join(";")),
+        };
+        self.line
+            .split('\t')
+            .enumerate()
+            .map(|(index, field)| match index {
+                7 => info.as_str(),
+                _ => field,
+            })
+            .collect::<Vec<&str>>()
+            .join("\t")
+    }
+
+    /// Returns the non reference alleles in the genotype of each sample, as
+    /// sample index and allele index. Symbolic alleles (e.g. `<*>`) are
+    /// skipped.
+    pub fn sample_alt_alleles(&self) -> Vec<(usize, usize)> {
+        let mut snps = vec![];
+        for sample_index in 0..self.samples.len() {
+            let mut alleles = self.sample_alleles(sample_index);
+            alleles.sort_unstable();
+            alleles.dedup();
+            for allele in alleles {
+                match self.allele(allele) {
+                    Some(alt) if allele > 0 && !alt.starts_with('<') && alt != "*" => {
+                        snps.push((sample_index, allele))
+                    }
+                    _ => continue,
+                }
+            }
+        }
+        snps
+    }
+}
+
+/// Reads a VCF file, keeping the header lines
+pub struct VcfLineReader {
+    reader: Box<dyn BufRead>,
+    /// Meta-information lines, starting with `##`
+    pub header: Vec<String>,
+    /// The `#CHROM` line
+    pub column_header: String,
+    pub sample_names: Vec<String>,
+    /// First record, read while looking for the end of the header
+    first_line: Option<String>,
+}
+
+impl VcfLineReader {
+    pub fn new<P: AsRef<Path>>(file_name: P) -> Result<Self> {
+        let mut reader = bio_rascal::io::open_file(&file_name)
+            .with_context(|| format!("Cannot open file {}", file_name.as_ref().display()))?;
+        let mut header = vec![];
+        let mut column_header = String::new();
+        let mut sample_names = vec![];
+        let mut first_line = None;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 {
+                break;
+            }
+            let line = line.trim_end_matches(&['\n', '\r'][..]).to_string();
+            if line.starts_with("##") {
+                header.push(line);
+            } else if line.starts_with("#CHROM") {
+                sample_names = line.split('\t').skip(9).map(|s| s.to_string()).collect();
+                column_header = line;
+            } else if !line.is_empty() {
+                first_line = Some(line);
+                break;
+            }
+        }
+        Ok(Self {
+            reader,
+            header,
+            column_header,
+            sample_names,
+            first_line,
+        })
+    }
+}
+
+impl Iterator for VcfLineReader {
+    type Item = Result<VcfLine>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(line) = self.first_line.take() {
+            return Some(VcfLine::from_line(line));
+        }
+        loop {
+            let mut line = String::new();
+            match self.reader.read_line(&mut line) {
+                Err(err) => return Some(Err(err.into())),
+                Ok(0) => return None,
+                Ok(_) => {
+                    let line = line.trim_end_matches(&['\n', '\r'][..]);
+                    if line.is_empty() {
+                        continue;
+                    }
+                    return Some(VcfLine::from_line(line.to_string()));
+                }
+            }
+        }
+    }
+}
+
+/// Writes VCF lines, compressed if the file name ends in `.gz` or `.zst`
+/// (note that `.gz` is plain gzip, not BGZF)
+pub struct VcfWriter {
+    writer: OutputFile,
+}
+
+impl VcfWriter {
+    /// Creates the file and writes the header of the input file, the
+    /// `extra_header` lines are added before the `#CHROM` line
+    pub fn new<P: AsRef<Path>>(
+        file_name: P,
+        reader: &VcfLineReader,
+        extra_header: &[String],
+    ) -> Result<Self> {
+        let mut writer = create_output_file(file_name)?;
+        for line in reader.header.iter().chain(extra_header.iter()) {
+            writeln!(writer, "{}", line)?;
+        }
+        writeln!(writer, "{}", reader.column_header)?;
+        Ok(Self { writer })
+    }
+
+    pub fn write_line(&mut self, line: &str) -> Result<()> {
+        writeln!(self.writer, "{}", line).context("Problem writing VCF line")
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.writer
+            .finish()
+            .context("Problem flushing VCF file to disk")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static LINE: &str = "contig_1\t100\t.\tA\tG,T,<*>\t.\tPASS\tDP=30;INDEL\tGT:AD:DP\t0/1:6,4,0,0:10\t2|2:.:8\t./.:0,0,0,0:.";
+
+    #[test]
+    fn test_from_line() {
+        let record = VcfLine::from_line(LINE.to_string()).unwrap();
+        assert_eq!(record.chrom, "contig_1");
+        assert_eq!(record.pos, 100);
+        assert_eq!(record.alt_alleles, ["G", "T", "<*>"]);
+        assert!(record.qual.is_nan());
+        assert_eq!(record.depth(), 30);
+        assert_eq!(record.info_value("INDEL"), Some(""));
+        assert_eq!(record.info_value("DP4"), None);
+        assert_eq!(record.allele(0), Some("A"));
+        assert_eq!(record.allele(2), Some("T"));
+        assert_eq!(record.allele(4), None);
+
+        let record = VcfLine::from_line("contig_1\t5\t.\tAC\t.\t12.5\t.\t.".to_string()).unwrap();
+        assert_eq!(record.qual, 12.5);
+        assert!(record.alt_alleles.is_empty());
+        assert_eq!(record.depth(), 0);
+        assert!(record.is_indel());
+        assert!(record.sample_alt_alleles().is_empty());
+
+        assert!(VcfLine::from_line("contig_1\t5\t.\tA\tG\t10".to_string()).is_err());
+        assert!(VcfLine::from_line("contig_1\tx\t.\tA\tG\t10\t.\t.".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_is_indel() {
+        let record = |ref_allele: &str, alt: &str| {
+            VcfLine::from_line(format!("c\t1\t.\t{}\t{}\t10\t.\tDP=1", ref_allele, alt)).unwrap()
+        };
+        assert!(!record("A", "G,T").is_indel());
+        assert!(record("A", "G,AT").is_indel());
+        assert!(record("AT", "A").is_indel());
+    }
+
+    #[test]
+    fn test_sample_fields() {
+        let record = VcfLine::from_line(LINE.to_string()).unwrap();
+        assert_eq!(record.sample_value(0, "DP"), Some("10"));
+        assert_eq!(record.sample_value(2, "DP"), None);
+        assert_eq!(record.sample_value(0, "PL"), None);
+        assert_eq!(record.sample_value(3, "GT"), None);
+
+        assert_eq!(record.sample_alleles(0), [0, 1]);
+        assert_eq!(record.sample_alleles(1), [2, 2]);
+        assert!(record.sample_alleles(2).is_empty());
+
+        assert_eq!(record.allele_depths(0), Some(vec![6, 4, 0, 0]));
+        assert_eq!(record.allele_depths(1), None);
+        // from `AD`, then `DP`
+        assert_eq!(record.sample_depth(0), Some(10));
+        assert_eq!(record.sample_depth(1), Some(8));
+        assert_eq!(record.sample_depth(2), Some(0));
+        assert_eq!(record.allele_frequency(0, 1), Some(0.4));
+        assert_eq!(record.allele_frequency(1, 1), None);
+        assert_eq!(record.allele_frequency(2, 1), None);
+    }
+
+    #[test]
+    fn test_sample_alt_alleles() {
+        // the symbolic allele and the duplicated one are skipped
+        let line = LINE.replace("./.:0,0,0,0:.", "0/3:0,0,0,0:.");
+        let record = VcfLine::from_line(line).unwrap();
+        assert_eq!(record.sample_alt_alleles(), [(0, 1), (1, 2)]);
+        let record =
+            VcfLine::from_line("c\t1\t.\tA\tG,*\t10\t.\tDP=1\tGT\t1/2\t0/0".to_string()).unwrap();
+        assert_eq!(record.sample_alt_alleles(), [(0, 1)]);
+    }
+
+    #[test]
+    fn test_line_with_info() {
+        let record = VcfLine::from_line(LINE.to_string()).unwrap();
+        assert_eq!(record.line_with_info(&[]), LINE);
+        let entries = ["PNPS_CLASS=SYN".to_string()];
+        let line = record.line_with_info(&entries);
+        assert_eq!(line.split('\t').nth(7), Some("DP=30;INDEL;PNPS_CLASS=SYN"));
+        assert_eq!(line.split('\t').nth(9), Some("0/1:6,4,0,0:10"));
+        let record = VcfLine::from_line("c\t1\t.\tA\tG\t10\t.\t.".to_string()).unwrap();
+        assert_eq!(
+            record.line_with_info(&entries),
+            "c\t1\t.\tA\tG\t10\t.\tPNPS_CLASS=SYN"
+        );
+    }
+}